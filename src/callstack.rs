@@ -0,0 +1,281 @@
+// Shadow call stack kept beside the real one. Every CALL, RST and interrupt
+// pushes a frame and every RET pops one, so a backtrace can be shown even
+// when the stack in memory has been trampled, and returns that don't match
+// the call they should be returning from are reported.
+//
+// Execution history has it log the frames an instruction pushes and pops, so
+// stepping backwards rewinds it along with the CPU.
+
+use std::fmt;
+
+use crate::symbols::Symbols;
+
+// Frames kept before the oldest are dropped, for programs that never return
+const MAX_DEPTH: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameKind {
+    Call,
+    Rst,
+    Interrupt,
+}
+
+impl fmt::Display for FrameKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            FrameKind::Call => "call",
+            FrameKind::Rst => "rst",
+            FrameKind::Interrupt => "interrupt",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    // Instruction that made the call, or that was interrupted
+    pub call_site: u16,
+    pub target: u16,
+    // Address pushed, where a RET should go back to
+    pub return_address: u16,
+    // SP after the push, where the return address is stored
+    pub sp: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MismatchKind {
+    // No frame to return from
+    Empty,
+    // Returned from the right place in the stack to somewhere other than the
+    // address pushed, the return address was overwritten
+    WrongAddress,
+    // SP was above the top frame, returning out of several frames at once
+    Unwound { frames: usize },
+    // SP was below the top frame, returning through something pushed since
+    // the call
+    AboveFrame,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub kind: MismatchKind,
+    // The returning instruction
+    pub pc: u16,
+    // SP the return address was popped from
+    pub sp: u16,
+    pub return_address: u16,
+    // Frame the return should have matched
+    pub expected: Option<Frame>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Return at 0x{:04x} to 0x{:04x} from SP 0x{:04x} ",
+            self.pc, self.return_address, self.sp
+        )?;
+        match &self.kind {
+            MismatchKind::Empty => return write!(f, "without a matching call"),
+            MismatchKind::WrongAddress => write!(f, "went to the wrong address")?,
+            MismatchKind::Unwound { frames } => write!(f, "skipped {} frame(s)", frames)?,
+            MismatchKind::AboveFrame => write!(f, "popped data pushed since the call")?,
+        }
+        if let Some(frame) = &self.expected {
+            write!(
+                f,
+                ", expected 0x{:04x} from SP 0x{:04x} ({} at 0x{:04x})",
+                frame.return_address, frame.sp, frame.kind, frame.call_site
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// A change to the frames, logged so it can be undone
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrameChange {
+    Pushed,
+    Popped(Frame),
+    // Oldest frame dropped to make room
+    Dropped(Frame),
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct CallStack {
+    // Outermost first
+    pub frames: Vec<Frame>,
+    // Bad returns since the debugger last cleared them
+    pub mismatches: Vec<Mismatch>,
+    // Changes to the frames, in order, while execution history wants them
+    pub log: Option<Vec<FrameChange>>,
+}
+
+impl CallStack {
+    pub fn push(&mut self, frame: Frame) {
+        if self.frames.len() == MAX_DEPTH {
+            let oldest = self.frames.remove(0);
+            self.record(FrameChange::Dropped(oldest));
+        }
+        self.frames.push(frame);
+        self.record(FrameChange::Pushed);
+    }
+
+    // Matches a RET at pc, popping return_address from sp, against the
+    // frames, dropping the ones it returns out of
+    pub fn pop(&mut self, pc: u16, sp: u16, return_address: u16) {
+        let expected = self.frames.last().copied();
+        let kind = match expected {
+            None => Some(MismatchKind::Empty),
+            Some(frame) if frame.sp == sp => {
+                self.frames.pop();
+                self.record(FrameChange::Popped(frame));
+                (frame.return_address != return_address).then_some(MismatchKind::WrongAddress)
+            }
+            Some(frame) if frame.sp < sp => {
+                // Frames are pushed at decreasing SPs, so drop every one
+                // below the slot being popped, and the frame using it if any
+                let mut frames = 0;
+                while let Some(&frame) = self.frames.last() {
+                    if frame.sp > sp {
+                        break;
+                    }
+                    let exact = frame.sp == sp;
+                    self.frames.pop();
+                    self.record(FrameChange::Popped(frame));
+                    if exact {
+                        break;
+                    }
+                    frames += 1;
+                }
+                Some(MismatchKind::Unwound { frames })
+            }
+            Some(_) => Some(MismatchKind::AboveFrame),
+        };
+        if let Some(kind) = kind {
+            self.mismatches.push(Mismatch {
+                kind,
+                pc,
+                sp,
+                return_address,
+                expected,
+            });
+        }
+    }
+
+    // Puts back the frames as they were before the logged changes
+    pub fn undo(&mut self, changes: &[FrameChange]) {
+        for change in changes.iter().rev() {
+            match change {
+                FrameChange::Pushed => {
+                    self.frames.pop();
+                }
+                FrameChange::Popped(frame) => self.frames.push(*frame),
+                FrameChange::Dropped(frame) => self.frames.insert(0, *frame),
+            }
+        }
+    }
+
+    fn record(&mut self, change: FrameChange) {
+        if let Some(log) = &mut self.log {
+            log.push(change);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.mismatches.clear();
+    }
+
+    // Innermost first: where execution is at pc, then each call site
+    // leading to it
+    pub fn backtrace(&self, pc: u16, symbols: &Symbols) -> Vec<String> {
+        let mut lines = vec![format!("#0  {}", symbols.describe(pc))];
+        for (depth, frame) in self.frames.iter().rev().enumerate() {
+            lines.push(format!(
+                "#{:<2} {}  {} to {}, SP 0x{:04x}",
+                depth + 1,
+                symbols.describe(frame.call_site),
+                frame.kind,
+                symbols.describe(frame.target),
+                frame.sp
+            ));
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(return_address: u16, sp: u16) -> Frame {
+        Frame {
+            kind: FrameKind::Call,
+            call_site: return_address - 3,
+            target: 0x1000,
+            return_address,
+            sp,
+        }
+    }
+
+    // Stack of calls returning to 0x0103, 0x0203 and 0x0303
+    fn nested() -> CallStack {
+        let mut stack = CallStack::default();
+        stack.push(frame(0x0103, 0x00fe));
+        stack.push(frame(0x0203, 0x00fc));
+        stack.push(frame(0x0303, 0x00fa));
+        stack
+    }
+
+    fn kinds(stack: &CallStack) -> Vec<MismatchKind> {
+        stack.mismatches.iter().map(|m| m.kind.clone()).collect()
+    }
+
+    #[test]
+    fn matching_returns_pop_without_a_mismatch() {
+        let mut stack = nested();
+        stack.pop(0x1000, 0x00fa, 0x0303);
+        stack.pop(0x1000, 0x00fc, 0x0203);
+        assert_eq!(stack.frames, vec![frame(0x0103, 0x00fe)]);
+        assert!(stack.mismatches.is_empty());
+    }
+
+    #[test]
+    fn reports_a_return_with_no_call() {
+        let mut stack = CallStack::default();
+        stack.pop(0x1000, 0x00fe, 0x0103);
+        assert_eq!(kinds(&stack), vec![MismatchKind::Empty]);
+        assert_eq!(stack.mismatches[0].expected, None);
+        assert_eq!(
+            stack.mismatches[0].to_string(),
+            "Return at 0x1000 to 0x0103 from SP 0x00fe without a matching call"
+        );
+    }
+
+    #[test]
+    fn reports_an_overwritten_return_address() {
+        let mut stack = nested();
+        stack.pop(0x1000, 0x00fa, 0x4000);
+        assert_eq!(kinds(&stack), vec![MismatchKind::WrongAddress]);
+        assert_eq!(stack.mismatches[0].expected, Some(frame(0x0303, 0x00fa)));
+        assert_eq!(stack.frames.len(), 2);
+    }
+
+    #[test]
+    fn reports_returning_out_of_several_frames() {
+        let mut stack = nested();
+        stack.pop(0x1000, 0x00fe, 0x0103);
+        assert_eq!(kinds(&stack), vec![MismatchKind::Unwound { frames: 2 }]);
+        assert!(stack.frames.is_empty());
+    }
+
+    #[test]
+    fn reports_popping_data_pushed_since_the_call() {
+        let mut stack = nested();
+        stack.pop(0x1000, 0x00f8, 0x1234);
+        assert_eq!(kinds(&stack), vec![MismatchKind::AboveFrame]);
+        assert_eq!(stack.frames.len(), 3);
+    }
+}
@@ -0,0 +1,224 @@
+// Code and data coverage. Each byte of memory collects flags for how it has
+// been used: fetched as an opcode, fetched as an operand, read as data and
+// written. Reports cover the segments of a ROM set, giving percentages, an
+// annotated disassembly and an lcov style tracefile.
+
+use crate::cpu::MEMORY_SIZE;
+use crate::disassembler::disassemble_instr;
+use crate::loader::{RomSet, Segment};
+
+pub const OPCODE: u8 = 0b0001;
+pub const OPERAND: u8 = 0b0010;
+pub const READ: u8 = 0b0100;
+pub const WRITTEN: u8 = 0b1000;
+
+pub struct Coverage {
+    flags: Vec<u8>,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Coverage {
+            flags: vec![0; MEMORY_SIZE],
+        }
+    }
+}
+
+impl Coverage {
+    pub fn mark(&mut self, address: u16, flag: u8) {
+        self.flags[address as usize] |= flag;
+    }
+
+    pub fn flags(&self, address: u16) -> u8 {
+        self.flags[address as usize]
+    }
+
+    // Percentage of the segments' bytes used each way
+    pub fn summary(&self, rom_set: &RomSet) -> Vec<String> {
+        let addresses: Vec<usize> = rom_set.segments.iter().flat_map(segment_addresses).collect();
+        let total = addresses.len();
+        let count = |mask: u8| addresses.iter().filter(|a| self.flags[**a] & mask != 0).count();
+        let untouched = addresses.iter().filter(|a| self.flags[**a] == 0).count();
+        let line = |name: &str, n: usize| {
+            format!("  {:<21} {:>6}  {:>5.1}%", name, n, n as f64 * 100.0 / total.max(1) as f64)
+        };
+        vec![
+            format!("Coverage of {} bytes in {} segments", total, rom_set.segments.len()),
+            line("executed", count(OPCODE | OPERAND)),
+            line("executed as opcode", count(OPCODE)),
+            line("executed as operand", count(OPERAND)),
+            line("read as data", count(READ)),
+            line("written", count(WRITTEN)),
+            line("untouched", untouched),
+        ]
+    }
+
+    // Summary followed by a disassembly of each segment, every instruction
+    // marked with how its bytes were used
+    pub fn report(&self, memory: &[u8], rom_set: &RomSet) -> String {
+        let mut lines = self.summary(rom_set);
+        lines.push(String::new());
+        lines.push("Columns: x executed as opcode, o as operand, r read, w written".to_string());
+        for segment in &rom_set.segments {
+            lines.push(String::new());
+            lines.push(format!("{}:", segment.name));
+            for (address, len, text) in self.instructions(memory, segment) {
+                let mut used = 0;
+                for i in 0..len {
+                    used |= self.flags[address + i];
+                }
+                let mark = |flag: u8, c: char| if used & flag != 0 { c } else { '-' };
+                let bytes: Vec<String> = (0..len).map(|i| format!("{:02x}", memory[address + i])).collect();
+                lines.push(format!(
+                    "{}{}{}{} {:04x}  {:<9} {}",
+                    mark(OPCODE, 'x'),
+                    mark(OPERAND, 'o'),
+                    mark(READ, 'r'),
+                    mark(WRITTEN, 'w'),
+                    address,
+                    bytes.join(" "),
+                    text
+                ));
+            }
+        }
+        let mut text = lines.join("\n");
+        text.push('\n');
+        text
+    }
+
+    // lcov tracefile with a record per segment. Each instruction is a line,
+    // numbered by its address plus one as lcov counts lines from 1, and hit
+    // once if executed.
+    pub fn lcov(&self, memory: &[u8], rom_set: &RomSet) -> String {
+        let mut lines = vec!["TN:".to_string()];
+        for segment in &rom_set.segments {
+            lines.push(format!("SF:{}", segment.name));
+            let mut found = 0;
+            let mut hit = 0;
+            for (address, _, _) in self.instructions(memory, segment) {
+                let executed = self.flags[address] & OPCODE != 0;
+                lines.push(format!("DA:{},{}", address + 1, executed as u8));
+                found += 1;
+                hit += executed as usize;
+            }
+            lines.push(format!("LH:{}", hit));
+            lines.push(format!("LF:{}", found));
+            lines.push("end_of_record".to_string());
+        }
+        let mut text = lines.join("\n");
+        text.push('\n');
+        text
+    }
+
+    // Splits a segment into instructions as address, length and text. An
+    // instruction that would swallow a byte executed as an opcode is shown
+    // as a data byte instead, so the listing follows what actually ran.
+    fn instructions(&self, memory: &[u8], segment: &Segment) -> Vec<(usize, usize, String)> {
+        let end = segment.address as usize + segment.data.len();
+        let mut instructions = Vec::new();
+        let mut address = segment.address as usize;
+        while address < end {
+            let (text, seek) = disassemble_instr(memory, address);
+            let len = 1 + seek as usize;
+            let fits = address + len <= end && (1..len).all(|i| self.flags[address + i] & OPCODE == 0);
+            if fits {
+                instructions.push((address, len, text));
+                address += len;
+            } else {
+                instructions.push((address, 1, format!("DB {:02x}", memory[address])));
+                address += 1;
+            }
+        }
+        instructions
+    }
+}
+
+fn segment_addresses(segment: &Segment) -> std::ops::Range<usize> {
+    let start = segment.address as usize;
+    start..start + segment.data.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::I8080;
+
+    // LDA 0008; STA 0009; HLT, a byte never reached, then the data
+    const PROGRAM: [u8; 10] = [0x3a, 0x08, 0x00, 0x32, 0x09, 0x00, 0x76, 0x00, 0x42, 0x00];
+
+    fn run() -> (I8080, RomSet) {
+        let mut cpu = I8080::new();
+        cpu.coverage = Some(Box::default());
+        cpu.memory[..PROGRAM.len()].copy_from_slice(&PROGRAM);
+        while !cpu.halted {
+            cpu.step().unwrap();
+        }
+        let rom_set = RomSet {
+            segments: vec![Segment {
+                name: "test".to_string(),
+                address: 0,
+                data: PROGRAM.to_vec(),
+                read_only: false,
+            }],
+            entry: None,
+        };
+        (cpu, rom_set)
+    }
+
+    #[test]
+    fn marks_opcodes_operands_and_data_separately() {
+        let (cpu, _) = run();
+        let coverage = cpu.coverage.as_ref().unwrap();
+        let flags: Vec<u8> = (0..10).map(|a| coverage.flags(a)).collect();
+        assert_eq!(
+            flags,
+            [OPCODE, OPERAND, OPERAND, OPCODE, OPERAND, OPERAND, OPCODE, 0, READ, WRITTEN]
+        );
+    }
+
+    #[test]
+    fn lists_each_instruction_with_how_it_was_used() {
+        let (cpu, rom_set) = run();
+        let report = cpu.coverage.as_ref().unwrap().report(&cpu.memory, &rom_set);
+        let expected = "\
+Coverage of 10 bytes in 1 segments
+  executed                   7   70.0%
+  executed as opcode         3   30.0%
+  executed as operand        4   40.0%
+  read as data               1   10.0%
+  written                    1   10.0%
+  untouched                  1   10.0%
+
+Columns: x executed as opcode, o as operand, r read, w written
+
+test:
+xo-- 0000  3a 08 00  LDA 0008
+xo-- 0003  32 09 00  STA 0009
+x--- 0006  76        HLT
+---- 0007  00        NOOP
+--r- 0008  42        MOV B,D
+---w 0009  42        MOV B,D
+";
+        assert_eq!(report, expected);
+    }
+
+    #[test]
+    fn writes_a_line_per_instruction_for_lcov() {
+        let (cpu, rom_set) = run();
+        let lcov = cpu.coverage.as_ref().unwrap().lcov(&cpu.memory, &rom_set);
+        let expected = "\
+TN:
+SF:test
+DA:1,1
+DA:4,1
+DA:7,1
+DA:8,0
+DA:9,0
+DA:10,0
+LH:3
+LF:6
+end_of_record
+";
+        assert_eq!(lcov, expected);
+    }
+}
@@ -1,14 +1,108 @@
+use std::fmt;
+use std::mem;
+
+use crate::coverage::{self, Coverage};
+use crate::devices::{Io, NullIo};
+use crate::heatmap::Heatmap;
+use crate::loader::RomSet;
+use crate::callstack::{CallStack, Frame, FrameKind};
+use crate::profiler::Profiler;
+use crate::provenance::Provenance;
+use crate::rom::RomMap;
+use crate::selfmod::SelfModDetector;
+use crate::stackguard::{StackGuard, StackViolation};
+use crate::statistics::Statistics;
+use crate::uninit::UninitDetector;
 use crate::utils::{merge_bytes, self};
+use crate::watchpoints::{Access, WatchHit, Watchpoint};
+
+pub const MEMORY_SIZE: usize = 0x10000;
+
+// States taken by each opcode. Conditional calls and returns are listed
+// with their not-taken timing, call() and ret() add the 6 extra states
+// taken when they go ahead, so CALL and RET are listed 6 short.
+const CYCLES: [u8; 256] = [
+    4, 10, 7, 5, 5, 5, 7, 4, 4, 10, 7, 5, 5, 5, 7, 4, // 0x00
+    4, 10, 7, 5, 5, 5, 7, 4, 4, 10, 7, 5, 5, 5, 7, 4, // 0x10
+    4, 10, 16, 5, 5, 5, 7, 4, 4, 10, 16, 5, 5, 5, 7, 4, // 0x20
+    4, 10, 13, 5, 10, 10, 10, 4, 4, 10, 13, 5, 5, 5, 7, 4, // 0x30
+    5, 5, 5, 5, 5, 5, 7, 5, 5, 5, 5, 5, 5, 5, 7, 5, // 0x40
+    5, 5, 5, 5, 5, 5, 7, 5, 5, 5, 5, 5, 5, 5, 7, 5, // 0x50
+    5, 5, 5, 5, 5, 5, 7, 5, 5, 5, 5, 5, 5, 5, 7, 5, // 0x60
+    7, 7, 7, 7, 7, 7, 7, 7, 5, 5, 5, 5, 5, 5, 7, 5, // 0x70
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, // 0x80
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, // 0x90
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, // 0xa0
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, // 0xb0
+    5, 10, 10, 10, 11, 11, 7, 11, 5, 4, 10, 10, 11, 11, 7, 11, // 0xc0
+    5, 10, 10, 10, 11, 11, 7, 11, 5, 4, 10, 10, 11, 11, 7, 11, // 0xd0
+    5, 10, 10, 18, 11, 11, 7, 11, 5, 5, 10, 4, 11, 11, 7, 11, // 0xe0
+    5, 10, 10, 4, 11, 11, 7, 11, 5, 5, 10, 4, 11, 11, 7, 11, // 0xf0
+];
 
-const MEMORY_SIZE: usize = 65_535;
+#[derive(Debug)]
+pub enum CpuError {
+    UnknownOpcode { opcode: u8, pc: u16 },
+    // Only raised when the stack guard is strict, with every violation the
+    // instruction made
+    Stack(Vec<StackViolation>),
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpuError::UnknownOpcode { opcode, pc } => {
+                write!(f, "Unknown opcode 0x{:02x} at 0x{:04x}", opcode, pc)
+            }
+            CpuError::Stack(violations) => {
+                let messages: Vec<String> = violations.iter().map(|v| v.to_string()).collect();
+                write!(f, "{}", messages.join("; "))
+            }
+        }
+    }
+}
 
 pub struct I8080 {
     pub flags: StatusFlags,
     pub registers: Registers,
     pub memory: [u8; MEMORY_SIZE],
+    // Set by HLT, stops run()
+    pub halted: bool,
+    pub interrupts_enabled: bool,
+    // States executed since power on
+    pub cycles: u64,
+    pub io: Box<dyn Io>,
+    pub watchpoints: Vec<Watchpoint>,
+    // Watchpoints triggered since the debugger last cleared them
+    pub watch_hits: Vec<WatchHit>,
+    // When set, write_byte() logs the address and old value of every byte
+    // it overwrites so the write can be undone
+    pub write_log: Option<Vec<(u16, u8)>>,
+    // When set, the last instruction to write each address
+    pub provenance: Option<Box<Provenance>>,
+    // When set, a shadow of the calls made and not yet returned from
+    pub call_stack: Option<CallStack>,
+    // When set, counts executions and cycles per address and calls made
+    pub profiler: Option<Box<Profiler>>,
+    // When set, how each byte has been used
+    pub coverage: Option<Box<Coverage>>,
+    // When set, counts of opcodes, branches, memory accesses and port I/O
+    pub statistics: Option<Box<Statistics>>,
+    // When set, reads, writes and fetches per address
+    pub heatmap: Option<Box<Heatmap>>,
+    // When set, reports code being overwritten and data being executed
+    pub self_mod: Option<Box<SelfModDetector>>,
+    // When set, reports reads of bytes never written or loaded
+    pub uninit: Option<Box<UninitDetector>>,
+    // When set, reports the stack leaving its region or misbehaving
+    pub stack_guard: Option<Box<StackGuard>>,
+    // When set, the addresses writes are refused at
+    pub rom: Option<Box<RomMap>>,
+    // Address of the instruction being executed
+    instruction_pc: u16,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct StatusFlags {
     pub Z: bool,
     pub C: bool,
@@ -17,7 +111,29 @@ pub struct StatusFlags {
     pub AC: bool,
 }
 
-#[derive(Debug)]
+impl StatusFlags {
+    // Packs the flags as PUSH PSW would: S Z 0 AC 0 P 1 C
+    pub fn to_byte(&self) -> u8 {
+        (self.S as u8) << 7
+            | (self.Z as u8) << 6
+            | (self.AC as u8) << 4
+            | (self.P as u8) << 2
+            | 0b10
+            | self.C as u8
+    }
+
+    pub fn from_byte(byte: u8) -> StatusFlags {
+        StatusFlags {
+            S: byte & 0x80 != 0,
+            Z: byte & 0x40 != 0,
+            AC: byte & 0x10 != 0,
+            P: byte & 0x04 != 0,
+            C: byte & 0x01 != 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Registers {
     pub A: u8,
     pub B: u8,
@@ -34,6 +150,12 @@ pub struct Registers {
     pub SP: u16,
 }
 
+impl Default for I8080 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl I8080 {
     pub fn new() -> I8080 {
         I8080 {
@@ -55,22 +177,61 @@ impl I8080 {
                 P: false,
                 AC: false,
             },
-            memory: [0; MEMORY_SIZE]
+            memory: [0; MEMORY_SIZE],
+            halted: false,
+            interrupts_enabled: false,
+            cycles: 0,
+            io: Box::new(NullIo),
+            watchpoints: Vec::new(),
+            watch_hits: Vec::new(),
+            write_log: None,
+            provenance: None,
+            call_stack: None,
+            profiler: None,
+            coverage: None,
+            statistics: None,
+            heatmap: None,
+            self_mod: None,
+            uninit: None,
+            stack_guard: None,
+            rom: None,
+            instruction_pc: 0,
+        }
+    }
+
+    // Copies each segment of a ROM set to its load address
+    pub fn load_rom_set(&mut self, rom_set: &RomSet) {
+        for segment in &rom_set.segments {
+            let start = segment.address as usize;
+            self.memory[start..start + segment.data.len()].copy_from_slice(&segment.data);
+        }
+        if let Some(uninit) = &mut self.uninit {
+            uninit.mark_loaded(rom_set);
+        }
+        if let Some(entry) = rom_set.entry {
+            self.registers.PC = entry;
         }
     }
     
-    pub fn load(&mut self, buffer: &Vec<u8>) {
+    pub fn load(&mut self, buffer: &[u8]) {
         for (pos, byte) in buffer.iter().enumerate() {
             self.memory[pos] = *byte;
         }
     }
 
-    fn execute_cycle(&mut self) {
+    // Executes a single instruction
+    pub fn step(&mut self) -> Result<(), CpuError> {
+        let start_cycles = self.cycles;
+        self.instruction_pc = self.registers.PC;
         let opcode = self.get_next_byte();
+        self.cycles += CYCLES[opcode as usize] as u64;
+        if let Some(statistics) = &mut self.statistics {
+            statistics.record_opcode(opcode);
+        }
 
         match opcode {
             0x00 => {},
-            0x76 => unimplemented!("halt"),
+            0x76 => self.halted = true,
 
             // Data transfer
             0x40 ..= 0x7f => { // MOV
@@ -113,27 +274,27 @@ impl I8080 {
                 let low = self.get_next_byte();
                 let high = self.get_next_byte();
                 let addr = merge_bytes(high, low);
-                self.registers.A = self.memory[addr as usize];
+                self.registers.A = self.read_byte(addr);
             }
             0x32 => { // STA
                 let low = self.get_next_byte();
                 let high = self.get_next_byte();
                 let addr = merge_bytes(high, low);
-                self.memory[addr as usize] = self.registers.A;
+                self.write_byte(addr, self.registers.A);
             }
             0x2a => { // LHLD
                 let low = self.get_next_byte();
                 let high = self.get_next_byte();
                 let addr = merge_bytes(high, low);
-                self.registers.L = self.memory[addr as usize];
-                self.registers.H = self.memory[(addr+1) as usize];
+                self.registers.L = self.read_byte(addr);
+                self.registers.H = self.read_byte(addr.wrapping_add(1));
             }
             0x22 => { // SHLD
                 let low = self.get_next_byte();
                 let high = self.get_next_byte();
                 let addr = merge_bytes(high, low);
-                self.memory[addr as usize] = self.registers.L;
-                self.memory[(addr+1) as usize] = self.registers.H; 
+                self.write_byte(addr, self.registers.L);
+                self.write_byte(addr.wrapping_add(1), self.registers.H);
             }
             0x0a | 0x1a => { // LDAX
                 let rp = (opcode >> 4) & 0b11;
@@ -147,7 +308,7 @@ impl I8080 {
                     _ => unreachable!(),
                 };
                 let addr = merge_bytes(high, low);
-                self.registers.A = self.memory[addr as usize];
+                self.registers.A = self.read_byte(addr);
             }
             0x02 | 0x12 => { // STAX
                 let rp = (opcode >> 4) & 0b11;
@@ -161,7 +322,7 @@ impl I8080 {
                     _ => unreachable!(),
                 };
                 let addr = merge_bytes(high, low);
-                self.memory[addr as usize] = self.registers.A;
+                self.write_byte(addr, self.registers.A);
             }
             0xeb => { // XCHG
                 let h = self.registers.H;
@@ -216,29 +377,10 @@ impl I8080 {
                 let result = value as u16 + 1;
                 let result_u8 = result as u8;
 
-                if result_u8 == 0 {
-                    self.flags.Z = true;
-                } else {
-                    self.flags.Z = false;
-                }
-
-                if result_u8 & 0x80 == 1 {
-                    self.flags.S = true;    
-                } else {
-                    self.flags.S = false;
-                }
-
-                if utils::check_even_parity(result_u8) {
-                    self.flags.P = true;
-                } else {
-                    self.flags.P = false;
-                } 
-
-                if (self.registers.A & 0x0f) + (value & 0x0f) > 0x0f {
-                    self.flags.AC = true;
-                } else {
-                    self.flags.AC = false;
-                }
+                self.flags.Z = result_u8 == 0;
+                self.flags.S = result_u8 & 0x80 != 0;
+                self.flags.P = utils::check_even_parity(result_u8);
+                self.flags.AC = (value & 0x0f) + 1 > 0x0f;
 
                 self.set_dest(dest, result_u8);
             }
@@ -246,7 +388,7 @@ impl I8080 {
                 let source = (opcode >> 4) & 0b11;
                 let (high, low) = self.get_source_pair(source);
                 let merged = merge_bytes(high, low);
-                let sum = merged+1;
+                let sum = merged.wrapping_add(1);
                 let split = sum.to_be_bytes();
                 let low = split[1];
                 let high = split[0];
@@ -276,146 +418,166 @@ impl I8080 {
                 self.jmp();
             },
             0xc2 => { // JNZ
-                if !self.flags.Z {
-                    self.jmp();
-                }
+                self.jmp_if(!self.flags.Z);
             }
             0xca => { // JZ
-                if self.flags.Z {
-                    self.jmp();
-                }
+                self.jmp_if(self.flags.Z);
             }
             0xd2 => { // JNC
-                if !self.flags.C {
-                    self.jmp();
-                }
+                self.jmp_if(!self.flags.C);
             }
             0xda => { // JC
-                if self.flags.C {
-                    self.jmp();
-                }
+                self.jmp_if(self.flags.C);
             }
             0xe2 => { // JPO
-                if !self.flags.P {
-                    self.jmp();
-                }
+                self.jmp_if(!self.flags.P);
             }
             0xea => { // JPE
-                if self.flags.P {
-                    self.jmp();
-                }
+                self.jmp_if(self.flags.P);
             }
             0xf2 => { // JP
-                if !self.flags.S {
-                    self.jmp();
-                }
+                self.jmp_if(!self.flags.S);
             }
             0xfa => { // JM
-                if self.flags.S {
-                    self.jmp();
-                }
+                self.jmp_if(self.flags.S);
             }
             0xcd => { // CALL
                 self.call();
             }
             0xc4 => { // CNZ
-                if !self.flags.Z {
-                    self.call();
-                }
+                self.call_if(!self.flags.Z);
             }
             0xcc => { // CZ
-                if self.flags.Z {
-                    self.call();
-                }
+                self.call_if(self.flags.Z);
             }
             0xd4 => { // CNC
-                if !self.flags.C {
-                    self.call();
-                }
+                self.call_if(!self.flags.C);
             }
             0xdc => { // CC
-                if self.flags.C {
-                    self.call();
-                }
+                self.call_if(self.flags.C);
             }
             0xe4 => { // CPO
-                if !self.flags.P {
-                    self.call();
-                }
+                self.call_if(!self.flags.P);
             }
             0xec => { // CPE
-                if self.flags.P {
-                    self.call();
-                }
+                self.call_if(self.flags.P);
             }
             0xf4 => { // CP
-                if !self.flags.S {
-                    self.call();
-                }
+                self.call_if(!self.flags.S);
             }
             0xfc => { // CM
-                if self.flags.S {
-                    self.call();
-                }
+                self.call_if(self.flags.S);
             }
             0xc9 => { // RET
                 self.ret();
             }
             0xc0 => { // RNZ
-                if !self.flags.Z {
-                    self.ret();
-                }
+                self.ret_if(!self.flags.Z);
             }
             0xc8 => { // RZ
-                if self.flags.Z {
-                    self.ret();
-                }
+                self.ret_if(self.flags.Z);
             }
             0xd0 => { // RNC
-                if !self.flags.C {
-                    self.ret();
-                }
+                self.ret_if(!self.flags.C);
             }
             0xd8 => { // RC
-                if self.flags.C {
-                    self.ret();
-                }
+                self.ret_if(self.flags.C);
             }
             0xe0 => { // RPO
-                if !self.flags.P {
-                    self.ret();
-                }
+                self.ret_if(!self.flags.P);
             }
             0xe8 => { // RPE
-                if self.flags.P {
-                    self.ret();
-                }
+                self.ret_if(self.flags.P);
             }
             0xf0 => { // RP
-                if !self.flags.S {
-                    self.ret();
-                }
+                self.ret_if(!self.flags.S);
             }
             0xf8 => { // RM
-                if self.flags.S {
-                    self.ret();
-                }
+                self.ret_if(self.flags.S);
             }
             0xc7 | 0xcf | 0xd7 | 0xdf | 0xe7 | 0xef | 0xf7 | 0xff => { // RST
                 self.rst(opcode);
+                self.enter(FrameKind::Rst);
             }
             0xe9 => {
                 self.registers.PC = merge_bytes(self.registers.H, self.registers.L);
             }
 
+            // I/O and machine control
+            0xdb => { // IN
+                let port = self.get_next_byte();
+                if let Some(statistics) = &mut self.statistics {
+                    statistics.record_port(port, Access::Read);
+                }
+                self.registers.A = self.io.input(port);
+            }
+            0xd3 => { // OUT
+                let port = self.get_next_byte();
+                if let Some(statistics) = &mut self.statistics {
+                    statistics.record_port(port, Access::Write);
+                }
+                self.io.output(port, self.registers.A);
+            }
+            0xfb => self.interrupts_enabled = true, // EI
+            0xf3 => self.interrupts_enabled = false, // DI
 
-            _ => {self.debug_state(); unimplemented!("0x{:02x}", opcode)},
+            _ => {
+                let pc = self.registers.PC.wrapping_sub(1);
+                self.registers.PC = pc;
+                return Err(CpuError::UnknownOpcode { opcode, pc });
+            }
         };
+
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(self.instruction_pc, self.cycles - start_cycles);
+        }
+        self.check_sp();
+        if let Some(guard) = &mut self.stack_guard {
+            if guard.strict && !guard.violations.is_empty() {
+                return Err(CpuError::Stack(mem::take(&mut guard.violations)));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn run(&mut self) -> Result<(), CpuError> {
+        while !self.halted {
+            self.step()?;
+        }
+        Ok(())
     }
 
-    pub fn run(&mut self) {
-        loop {
-            self.execute_cycle();
+    // Pops PC as RET would, for routines emulated outside the CPU
+    pub fn return_from_subroutine(&mut self) {
+        self.instruction_pc = self.registers.PC;
+        self.cycles += CYCLES[0xc9] as u64;
+        self.ret();
+        self.check_sp();
+    }
+
+    // Services an interrupt by executing RST <vector>, as the interrupting
+    // device would place it on the data bus. Ignored while interrupts are
+    // disabled, returns whether it was taken.
+    pub fn interrupt(&mut self, vector: u8) -> bool {
+        if !self.interrupts_enabled {
+            return false;
+        }
+        self.interrupts_enabled = false;
+        self.halted = false;
+        self.instruction_pc = self.registers.PC;
+        self.cycles += CYCLES[0xc7] as u64;
+        self.rst(0xc7 | (vector & 0b111) << 3);
+        self.enter(FrameKind::Interrupt);
+        self.check_sp();
+        true
+    }
+
+    // Checks SP against the stack guard once an instruction, or whatever
+    // stood in for one, has finished with it. In strict mode violations
+    // become an error at the end of the next step.
+    fn check_sp(&mut self) {
+        if let Some(guard) = &mut self.stack_guard {
+            guard.check_sp(self.instruction_pc, self.registers.SP);
         }
     }
 
@@ -423,85 +585,214 @@ impl I8080 {
     fn get_next_byte(&mut self) -> u8 {
         let pc = self.registers.PC;
         let byte = self.memory[pc as usize];
-        self.registers.PC = pc + 1;
+        if let Some(coverage) = &mut self.coverage {
+            let flag = if pc == self.instruction_pc { coverage::OPCODE } else { coverage::OPERAND };
+            coverage.mark(pc, flag);
+        }
+        if let Some(heatmap) = &mut self.heatmap {
+            heatmap.record_fetch(pc);
+        }
+        if let Some(self_mod) = &mut self.self_mod {
+            self_mod.fetch(self.instruction_pc, pc);
+        }
+        self.registers.PC = pc.wrapping_add(1);
         byte
     }
 
+    // All data accesses go through here, instruction fetches do not
+    fn read_byte(&mut self, addr: u16) -> u8 {
+        let value = self.memory[addr as usize];
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(addr, Access::Read, value, value);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.mark(addr, coverage::READ);
+        }
+        if let Some(statistics) = &mut self.statistics {
+            statistics.record_access(addr, Access::Read);
+        }
+        if let Some(heatmap) = &mut self.heatmap {
+            heatmap.record_access(addr, Access::Read);
+        }
+        if let Some(uninit) = &mut self.uninit {
+            uninit.read(self.instruction_pc, addr);
+        }
+        value
+    }
+
+    fn write_byte(&mut self, addr: u16, value: u8) {
+        // Watchpoints see writes to ROM too, though they change nothing
+        if !self.watchpoints.is_empty() {
+            let old = self.memory[addr as usize];
+            self.check_watchpoints(addr, Access::Write, old, value);
+        }
+        if let Some(rom) = &mut self.rom {
+            if !rom.write(self.instruction_pc, addr, value) {
+                return;
+            }
+        }
+        if let Some(log) = &mut self.write_log {
+            log.push((addr, self.memory[addr as usize]));
+        }
+        if let Some(provenance) = &mut self.provenance {
+            provenance.record(addr, self.instruction_pc, self.cycles);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.mark(addr, coverage::WRITTEN);
+        }
+        if let Some(statistics) = &mut self.statistics {
+            statistics.record_access(addr, Access::Write);
+        }
+        if let Some(heatmap) = &mut self.heatmap {
+            heatmap.record_access(addr, Access::Write);
+        }
+        if let Some(self_mod) = &mut self.self_mod {
+            self_mod.write(self.instruction_pc, addr);
+        }
+        if let Some(uninit) = &mut self.uninit {
+            uninit.mark(addr);
+        }
+        self.memory[addr as usize] = value;
+    }
+
+    fn check_watchpoints(&mut self, address: u16, access: Access, old: u8, new: u8) {
+        let triggered = self
+            .watchpoints
+            .iter()
+            .find(|w| w.contains(address) && w.kind.matches(access));
+        if let Some(watchpoint) = triggered {
+            self.watch_hits.push(WatchHit {
+                pc: self.instruction_pc,
+                address,
+                access,
+                kind: watchpoint.kind,
+                old,
+                new,
+            });
+        }
+    }
+
     fn jmp(&mut self) {
+        self.jmp_if(true);
+    }
+
+    // The address operand is consumed whether or not the jump is taken
+    fn jmp_if(&mut self, condition: bool) {
         let b2 = self.get_next_byte();
         let b3 = self.get_next_byte();
-        let addr = merge_bytes(b3, b2);
-        self.registers.PC = addr;
+        if let Some(statistics) = &mut self.statistics {
+            statistics.record_branch(self.memory[self.instruction_pc as usize], condition);
+        }
+        if condition {
+            self.registers.PC = merge_bytes(b3, b2);
+        }
     }
     
     fn call(&mut self) {
-        let sp = self.registers.SP;
-        let pc = self.registers.PC.to_be_bytes();
-        self.memory[(sp-1) as usize] = pc[0];
-        self.memory[(sp-2) as usize] = pc[1]; 
-        self.registers.SP = sp - 2;
-        self.jmp();            
+        self.call_if(true);
+    }
+
+    // Return address is the instruction after the operand, which is
+    // consumed whether or not the call is made
+    fn call_if(&mut self, condition: bool) {
+        let b2 = self.get_next_byte();
+        let b3 = self.get_next_byte();
+        if let Some(statistics) = &mut self.statistics {
+            statistics.record_branch(self.memory[self.instruction_pc as usize], condition);
+        }
+        if !condition {
+            return;
+        }
+        self.push_word(self.registers.PC);
+        self.cycles += 6;
+        self.registers.PC = merge_bytes(b3, b2);
+        self.enter(FrameKind::Call);
+    }
+
+    // Only the conditional returns, RET goes straight to ret()
+    fn ret_if(&mut self, condition: bool) {
+        if let Some(statistics) = &mut self.statistics {
+            statistics.record_branch(self.memory[self.instruction_pc as usize], condition);
+        }
+        if condition {
+            self.ret();
+        }
     }
 
     fn ret(&mut self) {
         let sp = self.registers.SP;
-        let sp_low = self.memory[sp as usize];
-        let sp_high = self.memory[(sp+1) as usize];
-        let pc = merge_bytes(sp_high, sp_low);
+        let pc = self.pop_word();
+        if let Some(call_stack) = &mut self.call_stack {
+            call_stack.pop(self.instruction_pc, sp, pc);
+        }
         self.registers.PC = pc;
-        self.registers.SP = sp + 2;
+        self.cycles += 6;
     }
 
     fn rst(&mut self, opcode: u8) {
-        let sp = self.registers.SP;
-        let pc = self.registers.PC.to_be_bytes();
-        self.memory[(sp-1) as usize] = pc[0];
-        self.memory[(sp-2) as usize] = pc[1]; 
-        self.registers.SP = sp - 2;
+        self.push_word(self.registers.PC);
         let pc = (opcode & 0b111000) as u16;
         self.registers.PC = pc;
     }
 
-    // Add a value to acc and update flags
-    fn add(&mut self, value: u8) {
-        // Prevent overflow
-        let result = self.registers.A as u16 + value as u16;
-        let result_u8 = result as u8;
-
-        if result_u8 == 0 {
-            self.flags.Z = true;
-        } else {
-            self.flags.Z = false;
+    // The stack wraps round the top of memory, as SP is only 16 bits
+    fn push_word(&mut self, value: u16) {
+        let [high, low] = value.to_be_bytes();
+        let sp = self.registers.SP.wrapping_sub(2);
+        self.write_byte(sp.wrapping_add(1), high);
+        self.write_byte(sp, low);
+        self.registers.SP = sp;
+        if let Some(guard) = &mut self.stack_guard {
+            guard.push(self.instruction_pc, sp);
         }
+    }
 
-        if result_u8 & 0x80 == 1 {
-            self.flags.S = true;    
-        } else {
-            self.flags.S = false;
+    fn pop_word(&mut self) -> u16 {
+        let sp = self.registers.SP;
+        if let Some(guard) = &mut self.stack_guard {
+            guard.pop(self.instruction_pc, sp);
         }
+        let low = self.read_byte(sp);
+        let high = self.read_byte(sp.wrapping_add(1));
+        self.registers.SP = sp.wrapping_add(2);
+        merge_bytes(high, low)
+    }
 
-        if result > 0xff {
-            self.flags.C = true;
-        } else {
-            self.flags.C = false;
+    // Records the call, RST or interrupt that just pushed its return address
+    // and jumped, in the call stack and profiler
+    fn enter(&mut self, kind: FrameKind) {
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record_call(self.instruction_pc, self.registers.PC);
         }
+        let Some(call_stack) = &mut self.call_stack else {
+            return;
+        };
+        let sp = self.registers.SP;
+        call_stack.push(Frame {
+            kind,
+            call_site: self.instruction_pc,
+            target: self.registers.PC,
+            return_address: merge_bytes(self.memory[sp.wrapping_add(1) as usize], self.memory[sp as usize]),
+            sp,
+        });
+    }
 
-        if utils::check_even_parity(result_u8) {
-            self.flags.P = true;
-        } else {
-            self.flags.P = false;
-        } 
+    // Add a value to acc and update flags
+    fn add(&mut self, value: u8) {
+        // Prevent overflow
+        let result = self.registers.A as u16 + value as u16;
+        let result_u8 = result as u8;
 
-        if (self.registers.A & 0x0f) + (value & 0x0f) > 0x0f {
-            self.flags.AC = true;
-        } else {
-            self.flags.AC = false;
-        }
+        self.flags.Z = result_u8 == 0;
+        self.flags.S = result_u8 & 0x80 != 0;
+        self.flags.C = result > 0xff;
+        self.flags.P = utils::check_even_parity(result_u8);
+        self.flags.AC = (self.registers.A & 0x0f) + (value & 0x0f) > 0x0f;
 
         self.registers.A = result_u8;
     }
 
-    fn sub(&mut self, data: u8) {
+    fn sub(&mut self, _data: u8) {
         unimplemented!();
     }
 
@@ -524,7 +815,7 @@ impl I8080 {
         }
     }
 
-    fn get_source(&self, source: u8) -> u8 {
+    fn get_source(&mut self, source: u8) -> u8 {
         match source {
             0b000 => self.registers.B,
             0b001 => self.registers.C,
@@ -532,7 +823,7 @@ impl I8080 {
             0b011 => self.registers.E,
             0b100=> self.registers.H,
             0b101=> self.registers.L,
-            0b110 => self.memory[merge_bytes(self.registers.H, self.registers.L) as usize],
+            0b110 => self.read_byte(merge_bytes(self.registers.H, self.registers.L)),
             0b111 => self.registers.A,
             _ => unreachable!(),
         }
@@ -546,7 +837,7 @@ impl I8080 {
             0b011 => self.registers.E = data,
             0b100 => self.registers.H = data,
             0b101 => self.registers.L = data,
-            0b110 => self.memory[merge_bytes(self.registers.H, self.registers.L) as usize] = data,
+            0b110 => self.write_byte(merge_bytes(self.registers.H, self.registers.L), data),
             0b111 => self.registers.A = data,
             _ => unreachable!(),
         };
@@ -557,3 +848,61 @@ impl I8080 {
         dbg!(&self.flags);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fetches_wrap_round_the_top_of_memory() {
+        let mut cpu = I8080::new();
+        // LXI H,1234 split across 0xffff and 0x0000
+        cpu.registers.PC = 0xffff;
+        cpu.memory[0xffff] = 0x21;
+        cpu.memory[0x0000] = 0x34;
+        cpu.memory[0x0001] = 0x12;
+        cpu.step().unwrap();
+        assert_eq!((cpu.registers.H, cpu.registers.L), (0x12, 0x34));
+        assert_eq!(cpu.registers.PC, 0x0002);
+    }
+
+    #[test]
+    fn word_accesses_wrap_round_the_top_of_memory() {
+        let mut cpu = I8080::new();
+        cpu.registers.H = 0xab;
+        cpu.registers.L = 0xcd;
+        // SHLD ffff; LXI H,0; LHLD ffff
+        cpu.memory[..9].copy_from_slice(&[0x22, 0xff, 0xff, 0x21, 0x00, 0x00, 0x2a, 0xff, 0xff]);
+        cpu.step().unwrap();
+        assert_eq!((cpu.memory[0xffff], cpu.memory[0x0000]), (0xcd, 0xab));
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!((cpu.registers.H, cpu.registers.L), (0xab, 0xcd));
+    }
+
+    #[test]
+    fn inr_sets_flags_from_its_own_operand() {
+        let mut cpu = I8080::new();
+        cpu.registers.A = 0x0f;
+        cpu.registers.B = 0x7f;
+        cpu.registers.C = 0x10;
+        // INR B; INR C
+        cpu.memory[..2].copy_from_slice(&[0x04, 0x0c]);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.B, 0x80);
+        assert!(cpu.flags.S && cpu.flags.AC && !cpu.flags.Z);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.C, 0x11);
+        assert!(!cpu.flags.S && !cpu.flags.AC);
+    }
+
+    #[test]
+    fn inx_wraps() {
+        let mut cpu = I8080::new();
+        cpu.registers.H = 0xff;
+        cpu.registers.L = 0xff;
+        cpu.memory[0] = 0x23;
+        cpu.step().unwrap();
+        assert_eq!((cpu.registers.H, cpu.registers.L), (0, 0));
+    }
+}
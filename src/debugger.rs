@@ -0,0 +1,285 @@
+// Run control shared by the debugging front-ends: breakpoints, watchpoints
+// and stepping a System until something interesting happens.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::mem;
+
+use crate::callstack::Mismatch;
+use crate::cpu::{CpuError, I8080};
+use crate::expr::Expr;
+use crate::history::History;
+use crate::machine::System;
+use crate::rom::RomWrite;
+use crate::savestate::Snapshot;
+use crate::selfmod::CodeEvent;
+use crate::stackguard::StackViolation;
+use crate::uninit::UninitRead;
+use crate::symbols::Symbols;
+use crate::watchpoints::WatchHit;
+
+#[derive(Debug)]
+pub enum Stop {
+    // Ran the requested number of instructions
+    Done,
+    Halted,
+    Breakpoint(u16),
+    // Every access that matched during the last instruction
+    Watchpoint(Vec<WatchHit>),
+    // Returns that didn't match the shadow call stack
+    BadReturn(Vec<Mismatch>),
+    // Code overwritten or data executed
+    SelfModifying(Vec<CodeEvent>),
+    // Reads of memory never written
+    UninitialisedRead(Vec<UninitRead>),
+    // The stack left its region or misbehaved
+    StackGuard(Vec<StackViolation>),
+    // Writes refused by ROM
+    RomWrite(Vec<RomWrite>),
+    Error(CpuError),
+    // Stepping backwards ran out of recorded history
+    HistoryStart,
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stop::Done => write!(f, "Stopped"),
+            Stop::Halted => write!(f, "Halted"),
+            Stop::Breakpoint(addr) => write!(f, "Breakpoint at 0x{:04x}", addr),
+            Stop::Watchpoint(hits) => {
+                let hits: Vec<String> = hits.iter().map(|hit| format!("Watchpoint: {}", hit)).collect();
+                write!(f, "{}", hits.join("\n"))
+            }
+            Stop::BadReturn(mismatches) => {
+                let lines: Vec<String> = mismatches.iter().map(|m| m.to_string()).collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            Stop::SelfModifying(events) => {
+                let lines: Vec<String> = events.iter().map(|e| e.to_string()).collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            Stop::UninitialisedRead(reads) => {
+                let lines: Vec<String> = reads.iter().map(|r| r.to_string()).collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            Stop::StackGuard(violations) => {
+                let lines: Vec<String> = violations.iter().map(|v| v.to_string()).collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            Stop::RomWrite(writes) => {
+                let lines: Vec<String> = writes.iter().map(|w| w.to_string()).collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            Stop::Error(e) => write!(f, "{}", e),
+            Stop::HistoryStart => write!(f, "Reached the start of recorded history"),
+        }
+    }
+}
+
+// Breakpoint condition, keeping the text it was parsed from for display
+#[derive(Debug, Clone)]
+pub struct Condition {
+    pub text: String,
+    pub expr: Expr,
+}
+
+impl Condition {
+    pub fn parse(text: &str) -> Result<Condition, String> {
+        Ok(Condition {
+            text: text.trim().to_string(),
+            expr: Expr::parse(text)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Breakpoint {
+    // Only stop when this holds
+    pub condition: Option<Condition>,
+    // Times the breakpoint was reached with its condition holding
+    pub hits: u64,
+    // Hits still to pass over before stopping
+    pub ignore: u64,
+}
+
+impl Breakpoint {
+    pub fn new(condition: Option<Condition>) -> Breakpoint {
+        Breakpoint { condition, ..Default::default() }
+    }
+
+    // Counts the hit and decides whether to stop for it
+    fn hit(&mut self, cpu: &I8080) -> bool {
+        if let Some(condition) = &self.condition {
+            if !condition.expr.is_true(cpu) {
+                return false;
+            }
+        }
+        self.hits += 1;
+        if self.ignore > 0 {
+            self.ignore -= 1;
+            return false;
+        }
+        true
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "hits {}", self.hits)?;
+        if self.ignore > 0 {
+            write!(f, ", ignore next {}", self.ignore)?;
+        }
+        if let Some(condition) = &self.condition {
+            write!(f, ", if {}", condition.text)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Debugger {
+    pub system: System,
+    pub breakpoints: BTreeMap<u16, Breakpoint>,
+    // Recording for stepping backwards, off unless enabled
+    pub history: Option<History>,
+    // Labels for backtraces and listings, empty unless loaded
+    pub symbols: Symbols,
+    // State to compare against, taken by the monitor's snap command
+    pub snapshot: Option<Snapshot>,
+}
+
+impl Debugger {
+    pub fn new(system: System) -> Debugger {
+        Debugger {
+            system,
+            breakpoints: BTreeMap::new(),
+            history: None,
+            symbols: Symbols::default(),
+            snapshot: None,
+        }
+    }
+
+    // Executes up to count instructions. Breakpoints are checked before each
+    // instruction except the first, so stepping off a breakpoint works, and
+    // only stop once their condition holds and ignore count has run out.
+    // Watchpoints, bad returns, self-modifying code, uninitialised reads,
+    // stack guard violations and reported ROM writes stop after the
+    // instruction that triggered them.
+    pub fn step(&mut self, count: u64) -> Stop {
+        self.execute(count, false)
+    }
+
+    // Like step, but checks for a breakpoint before the first instruction
+    // too, for carrying on a run split into chunks
+    pub fn step_on(&mut self, count: u64) -> Stop {
+        self.execute(count, true)
+    }
+
+    fn execute(&mut self, count: u64, check_first: bool) -> Stop {
+        for i in 0..count {
+            if self.system.finished() {
+                return Stop::Halted;
+            }
+            let pc = self.system.cpu.registers.PC;
+            if i > 0 || check_first {
+                if let Some(breakpoint) = self.breakpoints.get_mut(&pc) {
+                    if breakpoint.hit(&self.system.cpu) {
+                        return Stop::Breakpoint(pc);
+                    }
+                }
+            }
+            self.system.cpu.watch_hits.clear();
+            if let Some(history) = &mut self.history {
+                history.begin(&mut self.system);
+            }
+            let result = self.system.step();
+            if let Some(history) = &mut self.history {
+                history.commit(&mut self.system);
+            }
+            if let Err(e) = result {
+                return Stop::Error(e);
+            }
+            if !self.system.cpu.watch_hits.is_empty() {
+                return Stop::Watchpoint(mem::take(&mut self.system.cpu.watch_hits));
+            }
+            if let Some(call_stack) = &mut self.system.cpu.call_stack {
+                if !call_stack.mismatches.is_empty() {
+                    return Stop::BadReturn(mem::take(&mut call_stack.mismatches));
+                }
+            }
+            if let Some(self_mod) = &mut self.system.cpu.self_mod {
+                if !self_mod.events.is_empty() {
+                    return Stop::SelfModifying(mem::take(&mut self_mod.events));
+                }
+            }
+            if let Some(uninit) = &mut self.system.cpu.uninit {
+                if !uninit.reads.is_empty() {
+                    return Stop::UninitialisedRead(mem::take(&mut uninit.reads));
+                }
+            }
+            if let Some(guard) = &mut self.system.cpu.stack_guard {
+                if !guard.violations.is_empty() {
+                    return Stop::StackGuard(mem::take(&mut guard.violations));
+                }
+            }
+            if let Some(rom) = &mut self.system.cpu.rom {
+                if !rom.writes.is_empty() {
+                    return Stop::RomWrite(mem::take(&mut rom.writes));
+                }
+            }
+        }
+        if self.system.finished() {
+            return Stop::Halted;
+        }
+        Stop::Done
+    }
+
+    // Frames of the shadow call stack, innermost first, None when it is not
+    // being kept
+    pub fn backtrace(&self) -> Option<Vec<String>> {
+        let cpu = &self.system.cpu;
+        let call_stack = cpu.call_stack.as_ref()?;
+        Some(call_stack.backtrace(cpu.registers.PC, &self.symbols))
+    }
+
+    // Runs until a breakpoint, halt or error
+    pub fn cont(&mut self) -> Stop {
+        self.step(u64::MAX)
+    }
+
+    // Undoes up to count instructions
+    pub fn step_back(&mut self, count: u64) -> Stop {
+        self.rewind(count, false)
+    }
+
+    // Undoes instructions until arriving at a breakpoint whose condition
+    // holds. Hit and ignore counts are left alone.
+    pub fn reverse_cont(&mut self) -> Stop {
+        self.rewind(u64::MAX, true)
+    }
+
+    fn rewind(&mut self, count: u64, stop_at_breakpoints: bool) -> Stop {
+        let Some(history) = &mut self.history else {
+            return Stop::HistoryStart;
+        };
+        for _ in 0..count {
+            if !history.undo(&mut self.system) {
+                return Stop::HistoryStart;
+            }
+            let cpu = &self.system.cpu;
+            let pc = cpu.registers.PC;
+            if stop_at_breakpoints {
+                if let Some(breakpoint) = self.breakpoints.get(&pc) {
+                    let holds = match &breakpoint.condition {
+                        Some(condition) => condition.expr.is_true(cpu),
+                        None => true,
+                    };
+                    if holds {
+                        return Stop::Breakpoint(pc);
+                    }
+                }
+            }
+        }
+        Stop::Done
+    }
+}
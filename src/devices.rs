@@ -0,0 +1,84 @@
+// Hardware attached to the CPU's I/O ports
+
+pub trait Io {
+    // Value read by IN from a port
+    fn input(&mut self, port: u8) -> u8;
+    // Value written by OUT to a port
+    fn output(&mut self, port: u8, value: u8);
+    // Internal state for save states, empty for devices without any
+    fn save(&self) -> Vec<u8> {
+        Vec::new()
+    }
+    // Puts back what save() returned, false when it doesn't fit the device
+    fn restore(&mut self, state: &[u8]) -> bool {
+        state.is_empty()
+    }
+}
+
+// Nothing attached, reads float to zero and writes are dropped
+pub struct NullIo;
+
+impl Io for NullIo {
+    fn input(&mut self, _port: u8) -> u8 {
+        0
+    }
+
+    fn output(&mut self, _port: u8, _value: u8) {}
+}
+
+// Space Invaders cabinet: input ports and the external shift register used
+// to draw sprites at arbitrary bit offsets. Sound and watchdog writes are
+// accepted and ignored.
+pub struct InvadersIo {
+    // Ports 0-2 as read by IN, bits are set while a control is held
+    pub inputs: [u8; 3],
+    shift: u16,
+    shift_offset: u8,
+}
+
+impl Default for InvadersIo {
+    fn default() -> Self {
+        InvadersIo {
+            // Bit 3 of port 1 is wired high, port 2 holds the DIP switches
+            inputs: [0x0e, 0x08, 0x00],
+            shift: 0,
+            shift_offset: 0,
+        }
+    }
+}
+
+impl Io for InvadersIo {
+    fn input(&mut self, port: u8) -> u8 {
+        match port {
+            0..=2 => self.inputs[port as usize],
+            3 => (self.shift >> (8 - self.shift_offset)) as u8,
+            _ => 0,
+        }
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        match port {
+            2 => self.shift_offset = value & 0b111,
+            4 => self.shift = (value as u16) << 8 | self.shift >> 8,
+            _ => {}
+        }
+    }
+
+    // Inputs, shift register and offset
+    fn save(&self) -> Vec<u8> {
+        let mut state = self.inputs.to_vec();
+        state.extend_from_slice(&self.shift.to_le_bytes());
+        state.push(self.shift_offset);
+        state
+    }
+
+    fn restore(&mut self, state: &[u8]) -> bool {
+        let [a, b, c, low, high, offset] = *state else {
+            return false;
+        };
+        self.inputs = [a, b, c];
+        self.shift = u16::from_le_bytes([low, high]);
+        self.shift_offset = offset & 0b111;
+        true
+    }
+}
@@ -0,0 +1,327 @@
+// Returns the instruction's text and the size of its operand (how much
+// extra to seek by). Operands are zero padded hex, words high byte first, as
+// in "LXI SP,0030" and "MVI A,0f". Operands running past the end of the
+// buffer read as 0.
+pub fn disassemble_instr(buffer: &[u8], offset: usize) -> (String, u8) {
+    let opcode = buffer[offset];
+    
+    let mut seek = 0;
+    
+    // Using an array for data after opcode for making access simpler
+    let operands = [
+        buffer.get(offset + 1).copied().unwrap_or(0),
+        buffer.get(offset + 2).copied().unwrap_or(0),
+    ];
+    let text = match opcode {
+        //0x00 | 0x08 | 0x10 | 0x18 | 0x20 | 0x28 | 0x30 |
+            //0x38 | 0xcb | 0xd9 | 0xdd | 0xed | 0xfd
+            //=> "NOP".to_string(), 
+        0x01 => {seek = 2; format!("LXI B,{:02x}{:02x}", operands[1], operands[0])},
+        0x02 => "STAX B".to_string(),
+        0x03 => "INX B".to_string(),
+        0x04 => "INR B".to_string(),
+        0x05 => "DCR B".to_string(),
+        0x06 => {seek = 1; format!("MVI B,{:02x}", operands[0])},
+        0x07 => "RLC".to_string(),
+
+        0x09 => "DAD B".to_string(),
+        0x0a => "LDAX B".to_string(),
+        0x0b => "DCX B".to_string(),
+        0x0c => "INR C".to_string(),
+        0x0d => "DCR C".to_string(),
+        0x0e => {seek = 1; format!("MVI C,{:02x}", operands[0])},
+        0x0f => "RRC".to_string(),
+
+        0x11 => {seek = 2; format!("LXI D,{:02x}{:02x}", operands[1], operands[0])},
+        0x12 => "STAX D".to_string(),
+        0x13 => "INX D".to_string(),
+        0x14 => "INR D".to_string(),
+        0x15 => "DCR D".to_string(),
+        0x16 => {seek = 1; format!("MVI D,{:02x}", operands[0])},
+        0x17 => "RAL".to_string(),
+
+        0x19 => "DAD D".to_string(),
+        0x1a => "LDAX D".to_string(),
+        0x1b => "DCX D".to_string(),
+        0x1c => "INR E".to_string(),
+        0x1d => "DCR E".to_string(),
+        0x1e => {seek = 1; format!("MVI E,{:02x}", operands[0])},
+        0x1f => "RAR".to_string(),
+
+        0x21 => {seek = 2; format!("LXI H,{:02x}{:02x}", operands[1], operands[0])},
+        0x22 => {seek = 2; format!("SHLD {:02x}{:02x}", operands[1], operands[0])},
+        0x23 => "INX H".to_string(),
+        0x24 => "INR H".to_string(),
+        0x25 => "DCR H".to_string(),
+        0x26 => {seek = 1; format!("MVI H,{:02x}", operands[0])},
+        0x27 => "DAA".to_string(),
+
+        0x29 => "DAD H".to_string(),
+        0x2a => {seek = 2; format!("LHLD {:02x}{:02x}", operands[1], operands[0])},
+        0x2b => "DCX H".to_string(),
+        0x2c => "INR L".to_string(),
+        0x2d => "DCR L".to_string(),
+        0x2e => {seek = 1; format!("MVI L,{:02x}", operands[0])},
+        0x2f => "CMA".to_string(),
+
+        0x31 => {seek = 2; format!("LXI SP,{:02x}{:02x}", operands[1], operands[0])},
+        0x32 => {seek = 2; format!("STA {:02x}{:02x}", operands[1], operands[0])},
+        0x33 => "INX SP".to_string(),
+        0x34 => "INR M".to_string(),
+        0x35 => "DCR M".to_string(),
+        0x36 => {seek = 1; format!("MVI M,{:02x}", operands[0])},
+        0x37 => "STC".to_string(),
+
+        0x39 => "DAD SP".to_string(),
+        0x3a => {seek = 2; format!("LDA {:02x}{:02x}", operands[1], operands[0])},
+        0x3b => "DCX SP".to_string(),
+        0x3c => "INR A".to_string(),
+        0x3d => "DCR A".to_string(),
+        0x3e => {seek = 1; format!("MVI A,{:02x}", operands[0])},
+        0x3f => "CMC".to_string(),
+        0x40 => "MOV B,B".to_string(),
+        0x41 => "MOV B,C".to_string(),
+        0x42 => "MOV B,D".to_string(),
+        0x43 => "MOV B,E".to_string(),
+        0x44 => "MOV B,H".to_string(),
+        0x45 => "MOV B,L".to_string(),
+        0x46 => "MOV B,M".to_string(),
+        0x47 => "MOV B,A".to_string(),
+        0x48 => "MOV C,B".to_string(),
+        0x49 => "MOV C,C".to_string(),
+        0x4a => "MOV C,D".to_string(),
+        0x4b => "MOV C,E".to_string(),
+        0x4c => "MOV C,H".to_string(),
+        0x4d => "MOV C,L".to_string(),
+        0x4e => "MOV C,M".to_string(),
+        0x4f => "MOV C,A".to_string(),
+        0x50 => "MOV D,B".to_string(),
+        0x51 => "MOV D,C".to_string(),
+        0x52 => "MOV D,D".to_string(),
+        0x53 => "MOV D,E".to_string(),
+        0x54 => "MOV D,H".to_string(),
+        0x55 => "MOV D,L".to_string(),
+        0x56 => "MOV D,M".to_string(),
+        0x57 => "MOV D,A".to_string(),
+        0x58 => "MOV E,B".to_string(),
+        0x59 => "MOV E,C".to_string(),
+        0x5a => "MOV E,D".to_string(),
+        0x5b => "MOV E,E".to_string(),
+        0x5c => "MOV E,H".to_string(),
+        0x5d => "MOV E,L".to_string(),
+        0x5e => "MOV E,M".to_string(),
+        0x5f => "MOV E,A".to_string(),
+        0x60 => "MOV H,B".to_string(),
+        0x61 => "MOV H,C".to_string(),
+        0x62 => "MOV H,D".to_string(),
+        0x63 => "MOV H,E".to_string(),
+        0x64 => "MOV H,H".to_string(),
+        0x65 => "MOV H,L".to_string(),
+        0x66 => "MOV H,M".to_string(),
+        0x67 => "MOV H,A".to_string(),
+        0x68 => "MOV L,B".to_string(),
+        0x69 => "MOV L,C".to_string(),
+        0x6a => "MOV L,D".to_string(),
+        0x6b => "MOV L,E".to_string(),
+        0x6c => "MOV L,H".to_string(),
+        0x6d => "MOV L,L".to_string(),
+        0x6e => "MOV L,M".to_string(),
+        0x6f => "MOV L,A".to_string(),
+        0x70 => "MOV M,B".to_string(),
+        0x71 => "MOV M,C".to_string(),
+        0x72 => "MOV M,D".to_string(),
+        0x73 => "MOV M,E".to_string(),
+        0x74 => "MOV M,H".to_string(),
+        0x75 => "MOV M,L".to_string(),
+        0x76 => "HLT".to_string(),
+        0x77 => "MOV M,A".to_string(),
+        0x78 => "MOV A,B".to_string(),
+        0x79 => "MOV A,C".to_string(),
+        0x7a => "MOV A,D".to_string(),
+        0x7b => "MOV A,E".to_string(),
+        0x7c => "MOV A,H".to_string(),
+        0x7d => "MOV A,L".to_string(),
+        0x7e => "MOV A,M".to_string(),
+        0x7f => "MOV A,A".to_string(),
+        0x80 => "ADD B".to_string(),
+        0x81 => "ADD C".to_string(),
+        0x82 => "ADD D".to_string(),
+        0x83 => "ADD E".to_string(),
+        0x84 => "ADD H".to_string(),
+        0x85 => "ADD L".to_string(),
+        0x86 => "ADD M".to_string(),
+        0x87 => "ADD A".to_string(),
+        0x88 => "ADC B".to_string(),
+        0x89 => "ADC C".to_string(),
+        0x8a => "ADC D".to_string(),
+        0x8b => "ADC E".to_string(),
+        0x8c => "ADC H".to_string(),
+        0x8d => "ADC L".to_string(),
+        0x8e => "ADC M".to_string(),
+        0x8f => "ADC A".to_string(),
+        0x90 => "SUB B".to_string(),
+        0x91 => "SUB C".to_string(),
+        0x92 => "SUB D".to_string(),
+        0x93 => "SUB E".to_string(),
+        0x94 => "SUB H".to_string(),
+        0x95 => "SUB L".to_string(),
+        0x96 => "SUB M".to_string(),
+        0x97 => "SUB A".to_string(),
+        0x98 => "SBB B".to_string(),
+        0x99 => "SBB C".to_string(),
+        0x9a => "SBB D".to_string(),
+        0x9b => "SBB E".to_string(),
+        0x9c => "SBB H".to_string(),
+        0x9d => "SBB L".to_string(),
+        0x9e => "SBB M".to_string(),
+        0x9f => "SBB A".to_string(),
+        0xa0 => "ANA B".to_string(),
+        0xa1 => "ANA C".to_string(),
+        0xa2 => "ANA D".to_string(),
+        0xa3 => "ANA E".to_string(),
+        0xa4 => "ANA H".to_string(),
+        0xa5 => "ANA L".to_string(),
+        0xa6 => "ANA M".to_string(),
+        0xa7 => "ANA A".to_string(),
+        0xa8 => "XRA B".to_string(),
+        0xa9 => "XRA C".to_string(),
+        0xaa => "XRA D".to_string(),
+        0xab => "XRA E".to_string(),
+        0xac => "XRA H".to_string(),
+        0xad => "XRA L".to_string(),
+        0xae => "XRA M".to_string(),
+        0xaf => "XRA A".to_string(),
+        0xb0 => "ORA B".to_string(),
+        0xb1 => "ORA C".to_string(),
+        0xb2 => "ORA D".to_string(),
+        0xb3 => "ORA E".to_string(),
+        0xb4 => "ORA H".to_string(),
+        0xb5 => "ORA L".to_string(),
+        0xb6 => "ORA M".to_string(),
+        0xb7 => "ORA A".to_string(),
+        0xb8 => "CMP B".to_string(),
+        0xb9 => "CMP C".to_string(),
+        0xba => "CMP D".to_string(),
+        0xbb => "CMP E".to_string(),
+        0xbc => "CMP H".to_string(),
+        0xbd => "CMP L".to_string(),
+        0xbe => "CMP M".to_string(),
+        0xbf => "CMP A".to_string(),
+        0xc0 => "RNZ".to_string(),
+        0xc1 => "POP B".to_string(),
+        0xc2 => {seek = 2; format!("JNZ {:02x}{:02x}", operands[1], operands[0])},
+        0xc3 => {seek = 2; format!("JMP {:02x}{:02x}", operands[1], operands[0])},
+        0xc4 => {seek = 2; format!("CNZ {:02x}{:02x}", operands[1], operands[0])},
+        0xc5 => "PUSH B".to_string(),
+        0xc6 => {seek = 1; format!("ADI {:02x}", operands[0])},
+        0xc7 => "RST 0".to_string(),
+        0xc8 => "RZ".to_string(),
+        0xc9 => "RET".to_string(),
+        0xca => {seek = 2; format!("JZ {:02x}{:02x}", operands[1], operands[0])},
+
+        0xcc => {seek = 2; format!("CZ {:02x}{:02x}", operands[1], operands[0])},
+        0xcd => {seek = 2; format!("CALL {:02x}{:02x}", operands[1], operands[0])},
+        0xce => {seek = 1; format!("ACI {:02x}", operands[0])},
+        0xcf => "RST 1".to_string(),
+        0xd0 => "RNC".to_string(),
+        0xd1 => "POP D".to_string(),
+        0xd2 => {seek = 2; format!("JNC {:02x}{:02x}", operands[1], operands[0])},
+        0xd3 => {seek = 1; format!("OUT {:02x}", operands[0])},
+        0xd4 => {seek = 2; format!("CNC {:02x}{:02x}", operands[1], operands[0])},
+        0xd5 => "PUSH D".to_string(),
+        0xd6 => {seek = 1; format!("SUI {:02x}", operands[0])},
+        0xd7 => "RST 2".to_string(),
+        0xd8 => "RC".to_string(),
+
+        0xda => {seek = 2; format!("JC {:02x}{:02x}", operands[1], operands[0])},
+        0xdb => {seek = 1; format!("IN {:02x}", operands[0])},
+        0xdc => {seek = 2; format!("CC {:02x}{:02x}", operands[1], operands[0])},
+
+        0xde => {seek = 1; format!("SBI {:02x}", operands[0])},
+        0xdf => "RST 3".to_string(),
+        0xe0 => "RPO".to_string(),
+        0xe1 => "POP H".to_string(),
+        0xe2 => {seek = 2; format!("JPO {:02x}{:02x}", operands[1], operands[0])},
+        0xe3 => "XTHL".to_string(),
+        0xe4 => {seek = 2; format!("CPO {:02x}{:02x}", operands[1], operands[0])},
+        0xe5 => "PUSH H".to_string(),
+        0xe6 => {seek = 1; format!("ANI {:02x}", operands[0])},
+        0xe7 => "RST 4".to_string(),
+        0xe8 => "RPE".to_string(),
+        0xe9 => "PCHL".to_string(),
+        0xea => {seek = 2; format!("JPE {:02x}{:02x}", operands[1], operands[0])},
+        0xeb => "XCHG".to_string(),
+        0xec => {seek = 2; format!("CPE {:02x}{:02x}", operands[1], operands[0])},
+        0xee => {seek = 1; format!("XRI {:02x}", operands[0])},
+        0xef => "RST 5".to_string(),
+        0xf0 => "RP".to_string(),
+        0xf1 => "POP PSW".to_string(),
+        0xf2 => {seek = 2; format!("JP {:02x}{:02x}", operands[1], operands[0])},
+        0xf3 => "DI".to_string(),
+        0xf4 => {seek = 2; format!("CP {:02x}{:02x}", operands[1], operands[0])},
+        0xf5 => "PUSH PSW".to_string(),
+        0xf6 => {seek = 1; format!("ORI {:02x}", operands[0])},
+        0xf7 => "RST 6".to_string(),
+        0xf8 => "RM".to_string(),
+        0xf9 => "SPHL".to_string(),
+        0xfa => {seek = 2; format!("JM {:02x}{:02x}", operands[1], operands[0])},
+        0xfb => "EI".to_string(),
+        0xfc => {seek = 2; format!("CM {:02x}{:02x}", operands[1], operands[0])},
+        0xfe => {seek = 1; format!("CPI {:02x}", operands[0])},
+        0xff => "RST 7".to_string(),
+
+        _ => "NOOP".to_string(),
+        //_ => terminate(format!("Unknown instruction: 0x{:02x}", opcode).as_str()),
+    };
+
+    (text, seek)
+}
+
+// Like disassemble_instr for the instruction at address in memory, with
+// operands past the top of memory wrapping round to address 0
+pub fn disassemble_at(memory: &[u8], address: u16) -> (String, u8) {
+    let bytes: Vec<u8> = (0..3)
+        .map(|i| memory[(address as usize + i) % memory.len()])
+        .collect();
+    disassemble_instr(&bytes, 0)
+}
+
+// Disassembles count instructions from start as lines of address, bytes and
+// text, marking the one at pc
+pub fn listing(memory: &[u8], start: u16, count: usize, pc: u16) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut addr = start as usize;
+    for _ in 0..count {
+        let (text, seek) = disassemble_at(memory, addr as u16);
+        let len = 1 + seek as usize;
+        let bytes: Vec<String> = (0..len)
+            .map(|i| format!("{:02x}", memory[(addr + i) % memory.len()]))
+            .collect();
+        let marker = if addr == pc as usize { "=>" } else { "  " };
+        lines.push(format!("{} {:04x}  {:<9} {}", marker, addr, bytes.join(" "), text));
+        addr = (addr + len) % memory.len();
+    }
+    lines
+}
+
+// Instructions are variable length so decoding backwards is ambiguous. Tries
+// start points further and further back, keeping the furthest one whose
+// instructions line up exactly with addr.
+pub fn find_start_before(memory: &[u8], addr: u16, count: usize) -> u16 {
+    let mut best = addr;
+    for back in 1..=count * 3 {
+        let Some(start) = (addr as usize).checked_sub(back) else {
+            break;
+        };
+        let mut offset = start;
+        let mut decoded = 0;
+        while offset < addr as usize {
+            offset += 1 + disassemble_instr(memory, offset).1 as usize;
+            decoded += 1;
+        }
+        if offset == addr as usize && decoded <= count {
+            best = start as u16;
+        }
+    }
+    best
+}
@@ -1,21 +1,590 @@
-use cpu::I8080;
+use std::any::Any;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::process::exit;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-mod cpu;
-mod utils;
-use utils::load_file;
+use intel8080::callstack::CallStack;
+use intel8080::debugger::Debugger;
+use intel8080::gdbstub;
+use intel8080::history::History;
+use intel8080::ihex;
+use intel8080::loader::{load_image, load_raw, RomSet};
+use intel8080::machine::{MachineType, System};
+use intel8080::monitor;
+use intel8080::postmortem::{self, RecentInstructions};
+use intel8080::rom::{RomMap, RomWrites};
+use intel8080::romdb::verify;
+use intel8080::savestate::Snapshot;
+use intel8080::stackguard::StackGuard;
+use intel8080::symbols::Symbols;
+use intel8080::trace::{TraceFilter, Tracer};
+use intel8080::uninit::{Fill, UninitDetector};
+use intel8080::utils::{parse_number, terminate};
+
+const USAGE: &str = "\
+Usage: emulator [options] <image>
+       emulator [options] --manifest <file>
+       emulator [options] --load-state <file>
+
+Images ending in .hex or .ihx are read as Intel HEX, .s19, .s28, .s37, .srec
+or .mot as Motorola S-records and anything else as a raw binary.
+
+Options:
+  -h, --help                 show this message
+  --manifest <file>          load a ROM set from an address map manifest
+  --load <file>@<address>    load a raw binary at an address, may be repeated
+  --address <address>        load address of a raw image (default set by machine)
+  --pc <address>             entry point (default from image, else load address)
+  --sp <address>             initial stack pointer
+  --machine <name>           bare (default), cpm or invaders
+  --verify <name>            deprecated, use --machine, which checks the ROM set
+                             against the machine's checksums
+  --strict                   refuse to run ROMs that fail the machine's checksums,
+                             and make stack guard violations errors
+  --max-instructions <n>     stop after n instructions
+  --max-cycles <n>           stop after n states
+  --trace                    print each instruction to stderr before executing it,
+                             in the format described in src/trace.rs
+  --trace-file <file>        write the trace to a file instead of stderr
+  --trace-range <range>      only trace instructions at addresses start-end, may be
+                             repeated
+  --trace-cycles <range>     only trace instructions starting within cycles start-end
+  --debug                    start in the interactive monitor, h lists its commands
+  --gdb <port>               wait for a gdb remote protocol client on localhost
+  --history <n>              with --debug or --gdb, record the last n instructions
+                             so they can be stepped back through
+  --provenance               track the instruction that last wrote each address,
+                             queried with the monitor's who command
+  --call-stack               keep a shadow call stack, giving backtraces on errors
+                             and stopping the debugger on mismatched returns
+  --symbols <file>           read labels for backtraces, crash reports and profiles,
+                             one '<address> <name>' or '<name> EQU <address>'
+                             per line
+  --detect-smc               warn when the program overwrites code it has executed
+                             or executes bytes it wrote, stopping the debugger
+  --detect-uninit            warn when the program reads memory that was neither
+                             loaded nor written, ROM aside, stopping the debugger
+  --rom <range>              treat start-end as ROM, may be repeated. Segments marked
+                             ro in a manifest and the machine's own ROM are
+                             always ROM
+  --rom-writes <policy>      what to do with writes to ROM, ignore them as hardware
+                             does or report them, stopping the debugger (default
+                             ignore for invaders, report otherwise)
+  --stack-guard <range>      warn when SP leaves start-end, a push lands on loaded
+                             code or ROM or a return pops from above the initial
+                             SP (--sp, else end + 1), stopping the debugger
+  --fill <byte|random[:seed]>
+                             fill memory with a byte or random bytes before loading
+  --profile <file>           write the busiest addresses and subroutines to a file
+                             on exit, grouped by --symbols labels when given
+  --call-graph <file>        write the calls made between subroutines to a file on
+                             exit, in Graphviz DOT format
+  --coverage <file>          write how each loaded byte was used to a file on exit,
+                             as a summary and an annotated disassembly
+  --coverage-lcov <file>     write which instructions ran as an lcov tracefile,
+                             with address + 1 as the line number
+  --stats <file>             write opcode counts, branch outcomes, memory accesses
+                             per page and port I/O counts to a file on exit
+  --heatmap <file>           write a 256x256 image of memory use on exit, writes in
+                             red, reads in green and execution in blue, as PNG
+                             when the name ends in .png and PPM otherwise
+  --crash-history <n>        instructions shown in the crash report printed when
+                             the program fails (default 32)
+  --dump-hex <file> <range>  write memory start-end as Intel HEX on exit
+  --load-state <file>        carry on from a save state. Any image, manifest or
+                             --load is loaded over it and --pc and --sp applied
+                             after. The machine defaults to the one it was saved
+                             from
+  --save-state <file>        write a save state on exit, including when a limit
+                             is reached, so the run can be carried on later
+
+Exit status is 0 when the program halts, 1 on error and 2 when a limit is reached.
+";
+
+const EXIT_HALT: i32 = 0;
+const EXIT_ERROR: i32 = 1;
+const EXIT_LIMIT: i32 = 2;
+
+#[derive(Default)]
+struct Options {
+    image: Option<String>,
+    manifest: Option<String>,
+    loads: Vec<String>,
+    address: Option<u16>,
+    pc: Option<u16>,
+    sp: Option<u16>,
+    machine: Option<MachineType>,
+    strict: bool,
+    max_instructions: Option<u64>,
+    max_cycles: Option<u64>,
+    trace: bool,
+    trace_file: Option<String>,
+    trace_filter: TraceFilter,
+    debug: bool,
+    gdb: Option<u16>,
+    history: Option<usize>,
+    provenance: bool,
+    call_stack: bool,
+    symbols: Option<String>,
+    crash_history: Option<usize>,
+    detect_smc: bool,
+    detect_uninit: bool,
+    stack_guard: Option<(u16, u16)>,
+    roms: Vec<(u16, u16)>,
+    rom_writes: Option<RomWrites>,
+    fill: Option<Fill>,
+    profile: Option<String>,
+    call_graph: Option<String>,
+    coverage: Option<String>,
+    coverage_lcov: Option<String>,
+    stats: Option<String>,
+    heatmap: Option<String>,
+    dump: Option<(String, (u16, u16))>,
+    load_state: Option<String>,
+    save_state: Option<String>,
+}
 
 fn main() {
-    let mut buffer: Vec<u8> = Vec::new();
+    let options = parse_args(env::args().skip(1).collect());
+    let state = options
+        .load_state
+        .as_ref()
+        .map(|path| Snapshot::read(Path::new(path)).unwrap_or_else(|e| terminate(&e.to_string())));
+    let machine = options
+        .machine
+        .or(state.as_ref().map(|state| state.machine))
+        .unwrap_or(MachineType::Bare);
+    let load_address = options.address.unwrap_or(machine.load_address());
+
+    let rom_set = match (&options.manifest, &options.image) {
+        (Some(path), None) => RomSet::from_manifest(Path::new(path)),
+        (None, Some(path)) => load_image(Path::new(path), load_address),
+        (Some(_), Some(_)) => terminate("Give either an image or a manifest, not both"),
+        (None, None) if !options.loads.is_empty() || state.is_some() => Ok(RomSet::default()),
+        (None, None) => terminate("No file given, see --help"),
+    };
+    let mut rom_set = rom_set.unwrap_or_else(|e| terminate(&e.to_string()));
+    for spec in &options.loads {
+        let result = load_raw(spec).and_then(|raw| rom_set.extend(raw));
+        if let Err(e) = result {
+            terminate(&e.to_string());
+        }
+    }
+
+    if let Some(table) = machine.rom_table() {
+        let mismatches = verify(&rom_set, table);
+        for mismatch in &mismatches {
+            eprintln!("warning: {}", mismatch);
+        }
+        if options.strict && !mismatches.is_empty() {
+            terminate(&format!("ROM set does not match {}", table.name));
+        }
+    }
+
+    let mut system = System::new(machine);
+    if let Some(fill) = options.fill {
+        fill.apply(&mut system.cpu.memory);
+    }
+    if options.detect_uninit {
+        let mut uninit = UninitDetector::default();
+        for (start, end) in machine.rom_range().into_iter().chain(options.roms.iter().copied()) {
+            uninit.mark_range(start, end);
+        }
+        system.cpu.uninit = Some(Box::new(uninit));
+    }
+    if let Some(state) = &state {
+        if let Err(e) = system.load_state(state) {
+            terminate(&e.to_string());
+        }
+        // Anything loaded patches the restored memory, but the program
+        // carries on from where it was saved unless told otherwise
+        let pc = system.cpu.registers.PC;
+        system.cpu.load_rom_set(&rom_set);
+        system.cpu.registers.PC = options.pc.unwrap_or(pc);
+    } else {
+        system.cpu.load_rom_set(&rom_set);
+        system.reset(options.pc.or(rom_set.entry).unwrap_or(load_address));
+    }
+    if let Some(sp) = options.sp {
+        system.cpu.registers.SP = sp;
+    }
+    if options.provenance {
+        system.cpu.provenance = Some(Box::default());
+    }
+    if options.call_stack {
+        system.cpu.call_stack = Some(CallStack::default());
+    }
+    if options.profile.is_some() || options.call_graph.is_some() {
+        system.cpu.profiler = Some(Box::default());
+    }
+    if options.coverage.is_some() || options.coverage_lcov.is_some() {
+        system.cpu.coverage = Some(Box::default());
+    }
+    if options.detect_smc {
+        system.cpu.self_mod = Some(Box::default());
+    }
+    let read_only = rom_set
+        .segments
+        .iter()
+        .filter(|segment| segment.read_only)
+        .filter_map(|segment| segment.range());
+    let roms: Vec<(u16, u16)> = read_only.chain(options.roms.iter().copied()).collect();
+    if !roms.is_empty() || options.rom_writes.is_some() {
+        let rom = system
+            .cpu
+            .rom
+            .get_or_insert_with(|| Box::new(RomMap::new(machine.rom_writes())));
+        for (start, end) in roms {
+            rom.protect(start, end);
+        }
+        if let Some(policy) = options.rom_writes {
+            rom.policy = policy;
+        }
+    }
+    if let Some((start, end)) = options.stack_guard {
+        // Programs usually set SP themselves, so unless it was given the
+        // stack is taken to start at the end of the range
+        let top = options.sp.unwrap_or(end.wrapping_add(1));
+        let mut guard = StackGuard::new(start, end, top);
+        // Loaded code, and ROM whether or not anything was loaded into it
+        let loaded = rom_set.segments.iter().filter_map(|segment| segment.range());
+        let rom = machine.rom_range().into_iter().chain(options.roms.iter().copied());
+        guard.protected = loaded.chain(rom).collect();
+        guard.strict = options.strict;
+        system.cpu.stack_guard = Some(Box::new(guard));
+    }
+    if options.stats.is_some() {
+        system.cpu.statistics = Some(Box::default());
+    }
+    if options.heatmap.is_some() {
+        system.cpu.heatmap = Some(Box::default());
+    }
+    let symbols = match &options.symbols {
+        Some(path) => Symbols::from_file(Path::new(path)).unwrap_or_else(|e| terminate(&e.to_string())),
+        None => Symbols::default(),
+    };
+
+    let status = if let Some(port) = options.gdb {
+        let mut debugger = new_debugger(system, &options, symbols.clone());
+        if let Err(e) = gdbstub::serve(&mut debugger, port) {
+            terminate(&e.to_string());
+        }
+        system = debugger.system;
+        EXIT_HALT
+    } else if options.debug {
+        let mut debugger = new_debugger(system, &options, symbols.clone());
+        if let Err(e) = monitor::run(&mut debugger, io::stdin().lock(), &mut io::stdout()) {
+            terminate(&e.to_string());
+        }
+        system = debugger.system;
+        EXIT_HALT
+    } else {
+        run(&mut system, &options, &symbols)
+    };
+
+    if let Some((file, (start, end))) = &options.dump {
+        write_output(file, ihex::write(&system.cpu.memory, *start, *end, None));
+    }
+    if let Some(file) = &options.save_state {
+        if let Err(e) = system.save_state().write(Path::new(file)) {
+            terminate(&format!("Could not write save state: {}", e));
+        }
+    }
+    if let Some(profiler) = &system.cpu.profiler {
+        if let Some(file) = &options.profile {
+            write_output(file, profiler.report(&symbols));
+        }
+        if let Some(file) = &options.call_graph {
+            write_output(file, profiler.call_graph(&symbols));
+        }
+    }
+    if let Some(coverage) = &system.cpu.coverage {
+        if let Some(file) = &options.coverage {
+            write_output(file, coverage.report(&system.cpu.memory, &rom_set));
+        }
+        if let Some(file) = &options.coverage_lcov {
+            write_output(file, coverage.lcov(&system.cpu.memory, &rom_set));
+        }
+    }
+
+    if let (Some(statistics), Some(file)) = (&system.cpu.statistics, &options.stats) {
+        write_output(file, statistics.report());
+    }
+    if let (Some(heatmap), Some(file)) = (&system.cpu.heatmap, &options.heatmap) {
+        if let Err(e) = fs::write(file, heatmap.encode(Path::new(file))) {
+            terminate(&format!("Could not write {}: {}", file, e));
+        }
+    }
+
+    exit(status);
+}
+
+fn write_output(file: &str, text: String) {
+    if let Err(e) = fs::write(file, text) {
+        terminate(&format!("Could not write {}: {}", file, e));
+    }
+}
+
+fn new_debugger(system: System, options: &Options, symbols: Symbols) -> Debugger {
+    let mut debugger = Debugger::new(system);
+    debugger.history = options.history.map(History::new);
+    debugger.symbols = symbols;
+    debugger
+}
 
-    load_file(&mut buffer);
-    
-    emulator(&buffer);
+// Runs until the program halts, fails or hits a limit, returning the exit
+// status. Errors and panics while executing an instruction print a crash
+// report.
+fn run(system: &mut System, options: &Options, symbols: &Symbols) -> i32 {
+    let mut tracer = options.trace.then(|| new_tracer(options));
+    let status = run_traced(system, options, symbols, tracer.as_mut());
+    if let Some(tracer) = &mut tracer {
+        if let Err(e) = tracer.flush() {
+            terminate(&format!("Could not write trace: {}", e));
+        }
+    }
+    status
 }
 
-fn emulator(buffer: &Vec<u8>) {
-    let mut i8080 = I8080::new();
+// The trace is buffered so is flushed once the run is over however it ends
+fn run_traced(
+    system: &mut System,
+    options: &Options,
+    symbols: &Symbols,
+    mut tracer: Option<&mut Tracer<Box<dyn Write>>>,
+) -> i32 {
+    let mut recent = RecentInstructions::new(options.crash_history.unwrap_or(postmortem::DEFAULT_LENGTH));
+    let mut instructions = 0;
+    while !system.finished() {
+        if options.max_instructions.is_some_and(|max| instructions >= max)
+            || options.max_cycles.is_some_and(|max| system.cpu.cycles >= max)
+        {
+            eprintln!(
+                "Limit reached after {} instructions, {} cycles",
+                instructions, system.cpu.cycles
+            );
+            return EXIT_LIMIT;
+        }
 
-    i8080.load(buffer);
+        if let Some(tracer) = &mut tracer {
+            if let Err(e) = tracer.record(&system.cpu) {
+                terminate(&format!("Could not write trace: {}", e));
+            }
+        }
+        recent.record(&system.cpu);
+        let cause = match panic::catch_unwind(AssertUnwindSafe(|| system.step())) {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => Some(e.to_string()),
+            Err(payload) => Some(panic_message(payload.as_ref())),
+        };
+        if let Some(cause) = cause {
+            if let Some(tracer) = &mut tracer {
+                // So the trace comes before the report when both go to stderr
+                let _ = tracer.flush();
+            }
+            eprint!("{}", postmortem::report(&system.cpu, &recent, symbols, &cause));
+            return EXIT_ERROR;
+        }
+        if let Some(call_stack) = &mut system.cpu.call_stack {
+            for mismatch in call_stack.mismatches.drain(..) {
+                eprintln!("warning: {}", mismatch);
+            }
+        }
+        if let Some(self_mod) = &mut system.cpu.self_mod {
+            for event in self_mod.events.drain(..) {
+                eprintln!("warning: {}", event);
+            }
+        }
+        if let Some(uninit) = &mut system.cpu.uninit {
+            for read in uninit.reads.drain(..) {
+                eprintln!("warning: {}", read);
+            }
+        }
+        if let Some(guard) = &mut system.cpu.stack_guard {
+            for violation in guard.violations.drain(..) {
+                eprintln!("warning: {}", violation);
+            }
+        }
+        if let Some(rom) = &mut system.cpu.rom {
+            for write in rom.writes.drain(..) {
+                eprintln!("warning: {}", write);
+            }
+        }
+        instructions += 1;
+    }
+    EXIT_HALT
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    let message = match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>()) {
+        (Some(message), _) => message,
+        (_, Some(message)) => message.as_str(),
+        _ => "unknown cause",
+    };
+    format!("Emulator panicked: {}", message)
+}
+
+fn new_tracer(options: &Options) -> Tracer<Box<dyn Write>> {
+    let output: Box<dyn Write> = match &options.trace_file {
+        Some(path) => match File::create(path) {
+            Ok(file) => Box::new(BufWriter::new(file)),
+            Err(e) => terminate(&format!("Could not create {}: {}", path, e)),
+        },
+        None => Box::new(BufWriter::new(io::stderr())),
+    };
+    Tracer::new(output, options.trace_filter.clone())
+}
+
+fn parse_args(args: Vec<String>) -> Options {
+    let mut options = Options::default();
+
+    let mut rest = args.into_iter();
+    while let Some(arg) = rest.next() {
+        let mut value = |what: &str| {
+            rest.next()
+                .unwrap_or_else(|| terminate(&format!("{} needs {}", arg, what)))
+        };
+        match arg.as_str() {
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                exit(EXIT_HALT);
+            }
+            "--manifest" => options.manifest = Some(value("a file")),
+            "--load" => options.loads.push(value("a file@address")),
+            "--address" => options.address = Some(parse_address(&value("an address"))),
+            "--pc" => options.pc = Some(parse_address(&value("an address"))),
+            "--sp" => options.sp = Some(parse_address(&value("an address"))),
+            "--machine" => {
+                let name = value("a machine name");
+                options.machine = Some(MachineType::from_name(&name).unwrap_or_else(|| {
+                    terminate(&format!("Unknown machine '{}'", name))
+                }));
+            }
+            // Kept from before machine types, when it only checked checksums
+            "--verify" => {
+                eprintln!("warning: --verify is deprecated, use --machine");
+                let name = value("a machine name");
+                let machine = MachineType::from_name(&name).filter(|machine| machine.rom_table().is_some());
+                options.machine = Some(machine.unwrap_or_else(|| {
+                    terminate(&format!("No ROM checksums known for machine '{}'", name))
+                }));
+            }
+            "--strict" => options.strict = true,
+            "--max-instructions" => options.max_instructions = Some(parse_count(&value("a count"))),
+            "--max-cycles" => options.max_cycles = Some(parse_count(&value("a count"))),
+            "--trace" => options.trace = true,
+            "--trace-file" => {
+                options.trace = true;
+                options.trace_file = Some(value("a file"));
+            }
+            "--trace-range" => {
+                options.trace = true;
+                options.trace_filter.ranges.push(parse_range(&value("a range")));
+            }
+            "--trace-cycles" => {
+                options.trace = true;
+                options.trace_filter.cycles = Some(parse_cycle_window(&value("a range")));
+            }
+            "--debug" => options.debug = true,
+            "--gdb" => options.gdb = Some(parse_port(&value("a port"))),
+            "--history" => options.history = Some(parse_count(&value("a count")) as usize),
+            "--provenance" => options.provenance = true,
+            "--call-stack" => options.call_stack = true,
+            "--symbols" => options.symbols = Some(value("a file")),
+            "--detect-smc" => options.detect_smc = true,
+            "--detect-uninit" => options.detect_uninit = true,
+            "--rom" => options.roms.push(parse_range(&value("a range"))),
+            "--rom-writes" => {
+                let name = value("ignore or report");
+                options.rom_writes = Some(RomWrites::from_name(&name).unwrap_or_else(|| {
+                    terminate(&format!("Unknown ROM write policy '{}'", name))
+                }));
+            }
+            "--stack-guard" => options.stack_guard = Some(parse_range(&value("a range"))),
+            "--fill" => options.fill = Some(parse_fill(&value("a byte or random"))),
+            "--profile" => options.profile = Some(value("a file")),
+            "--call-graph" => options.call_graph = Some(value("a file")),
+            "--coverage" => options.coverage = Some(value("a file")),
+            "--coverage-lcov" => options.coverage_lcov = Some(value("a file")),
+            "--stats" => options.stats = Some(value("a file")),
+            "--heatmap" => options.heatmap = Some(value("a file")),
+            "--crash-history" => options.crash_history = Some(parse_count(&value("a count")) as usize),
+            "--dump-hex" => {
+                let file = value("a file");
+                let range = parse_range(&value("a range"));
+                options.dump = Some((file, range));
+            }
+            "--load-state" => options.load_state = Some(value("a file")),
+            "--save-state" => options.save_state = Some(value("a file")),
+            _ if arg.starts_with('-') => terminate(&format!("Unknown option '{}', see --help", arg)),
+            _ if options.image.is_some() => terminate("Only one image can be given"),
+            _ => options.image = Some(arg),
+        }
+    }
+
+    options
+}
+
+fn parse_address(text: &str) -> u16 {
+    match parse_number(text) {
+        Some(n) if n <= 0xffff => n as u16,
+        _ => terminate(&format!("Invalid address '{}'", text)),
+    }
+}
+
+fn parse_port(text: &str) -> u16 {
+    match text.parse() {
+        Ok(port) => port,
+        Err(_) => terminate(&format!("Invalid port '{}'", text)),
+    }
+}
+
+fn parse_count(text: &str) -> u64 {
+    match parse_number(text) {
+        Some(n) => n as u64,
+        None => terminate(&format!("Invalid count '{}'", text)),
+    }
+}
+
+// A byte, "random" for a seed from the clock or "random:<seed>". The seed
+// is printed so a failing run can be repeated.
+fn parse_fill(text: &str) -> Fill {
+    let seed = match text.strip_prefix("random") {
+        Some("") => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(1),
+        Some(seed) => match seed.strip_prefix(':').and_then(parse_number) {
+            Some(seed) => seed as u64,
+            None => terminate(&format!("Invalid fill '{}'", text)),
+        },
+        None => match parse_number(text) {
+            Some(byte) if byte <= 0xff => return Fill::Byte(byte as u8),
+            _ => terminate(&format!("Invalid fill '{}'", text)),
+        },
+    };
+    eprintln!("Filling memory with random bytes, seed {}", seed);
+    Fill::Random(seed)
+}
+
+// Parses an inclusive range of cycle counts written as start-end
+fn parse_cycle_window(text: &str) -> (u64, u64) {
+    let parse = |s: &str| parse_number(s).map(|n| n as u64);
+    match text.split_once('-').map(|(start, end)| (parse(start), parse(end))) {
+        Some((Some(start), Some(end))) if start <= end => (start, end),
+        _ => terminate(&format!("Invalid range '{}'", text)),
+    }
+}
 
-    i8080.run();
+// Parses an inclusive address range written as start-end
+fn parse_range(text: &str) -> (u16, u16) {
+    let parse = |s: &str| parse_number(s).filter(|n| *n <= 0xffff).map(|n| n as u16);
+    match text.split_once('-').map(|(start, end)| (parse(start), parse(end))) {
+        Some((Some(start), Some(end))) if start <= end => (start, end),
+        _ => terminate(&format!("Invalid range '{}'", text)),
+    }
 }
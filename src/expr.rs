@@ -0,0 +1,382 @@
+// Small expression language for breakpoint conditions and the like,
+// evaluated against a CPU's registers, flags and memory.
+//
+//   A == 0x10 && Z
+//   [HL] > 5
+//   BC == 0x1234 || !CY
+//
+// Registers are A B C D E H L, the pairs BC DE HL and SP and PC. Flags are
+// S Z AC P and CY (the carry flag, as C is the register) and read as 1 or 0.
+// [expr] reads the byte at an address. Numbers are decimal or 0x-prefixed
+// hex. Operators, loosest binding first, are || && | ^ & == != < <= > >=
+// + - and the unary ! and -. Any non-zero value counts as true. Names are
+// not case sensitive.
+
+use std::fmt;
+
+use crate::cpu::I8080;
+use crate::utils::{merge_bytes, parse_number};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    A,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    BC,
+    DE,
+    HL,
+    SP,
+    PC,
+    FlagS,
+    FlagZ,
+    FlagAC,
+    FlagP,
+    FlagCY,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Or,
+    And,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Not,
+    Neg,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(i64),
+    Operand(Operand),
+    // Byte in memory at the address
+    Memory(Box<Expr>),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, String> {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.expression(0)?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(expr),
+            Some(token) => Err(format!("Unexpected '{}'", token)),
+        }
+    }
+
+    pub fn eval(&self, cpu: &I8080) -> i64 {
+        match self {
+            Expr::Number(n) => *n,
+            Expr::Operand(operand) => read_operand(cpu, *operand),
+            Expr::Memory(addr) => cpu.memory[(addr.eval(cpu) & 0xffff) as usize] as i64,
+            Expr::Unary(op, expr) => {
+                let value = expr.eval(cpu);
+                match op {
+                    UnaryOp::Not => (value == 0) as i64,
+                    UnaryOp::Neg => value.wrapping_neg(),
+                }
+            }
+            Expr::Binary(op, left, right) => {
+                let left = left.eval(cpu);
+                // Short circuit so [..] reads are only done when needed
+                match op {
+                    BinaryOp::Or if left != 0 => return 1,
+                    BinaryOp::And if left == 0 => return 0,
+                    _ => {}
+                }
+                let right = right.eval(cpu);
+                match op {
+                    BinaryOp::Or | BinaryOp::And => (right != 0) as i64,
+                    BinaryOp::BitOr => left | right,
+                    BinaryOp::BitXor => left ^ right,
+                    BinaryOp::BitAnd => left & right,
+                    BinaryOp::Eq => (left == right) as i64,
+                    BinaryOp::Ne => (left != right) as i64,
+                    BinaryOp::Lt => (left < right) as i64,
+                    BinaryOp::Le => (left <= right) as i64,
+                    BinaryOp::Gt => (left > right) as i64,
+                    BinaryOp::Ge => (left >= right) as i64,
+                    BinaryOp::Add => left.wrapping_add(right),
+                    BinaryOp::Sub => left.wrapping_sub(right),
+                }
+            }
+        }
+    }
+
+    pub fn is_true(&self, cpu: &I8080) -> bool {
+        self.eval(cpu) != 0
+    }
+}
+
+fn read_operand(cpu: &I8080, operand: Operand) -> i64 {
+    let r = &cpu.registers;
+    let f = &cpu.flags;
+    let value = match operand {
+        Operand::A => r.A as u16,
+        Operand::B => r.B as u16,
+        Operand::C => r.C as u16,
+        Operand::D => r.D as u16,
+        Operand::E => r.E as u16,
+        Operand::H => r.H as u16,
+        Operand::L => r.L as u16,
+        Operand::BC => merge_bytes(r.B, r.C),
+        Operand::DE => merge_bytes(r.D, r.E),
+        Operand::HL => merge_bytes(r.H, r.L),
+        Operand::SP => r.SP,
+        Operand::PC => r.PC,
+        Operand::FlagS => f.S as u16,
+        Operand::FlagZ => f.Z as u16,
+        Operand::FlagAC => f.AC as u16,
+        Operand::FlagP => f.P as u16,
+        Operand::FlagCY => f.C as u16,
+    };
+    value as i64
+}
+
+fn operand_from_name(name: &str) -> Option<Operand> {
+    let operand = match name.to_ascii_uppercase().as_str() {
+        "A" => Operand::A,
+        "B" => Operand::B,
+        "C" => Operand::C,
+        "D" => Operand::D,
+        "E" => Operand::E,
+        "H" => Operand::H,
+        "L" => Operand::L,
+        "BC" => Operand::BC,
+        "DE" => Operand::DE,
+        "HL" => Operand::HL,
+        "SP" => Operand::SP,
+        "PC" => Operand::PC,
+        "S" => Operand::FlagS,
+        "Z" => Operand::FlagZ,
+        "AC" => Operand::FlagAC,
+        "P" => Operand::FlagP,
+        "CY" => Operand::FlagCY,
+        _ => return None,
+    };
+    Some(operand)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Symbol(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Number(n) => write!(f, "{}", n),
+            Token::Name(name) => write!(f, "{}", name),
+            Token::Symbol(symbol) => write!(f, "{}", symbol),
+        }
+    }
+}
+
+// Longest first so "<=" wins over "<"
+const SYMBOLS: [&str; 18] = [
+    "||", "&&", "==", "!=", "<=", ">=", "|", "^", "&", "<", ">", "+", "-", "!", "(", ")", "[", "]",
+];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if c.is_ascii_alphanumeric() || c == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            let word = &rest[..len];
+            let token = if c.is_ascii_digit() {
+                match parse_number(word) {
+                    Some(n) => Token::Number(n as i64),
+                    None => return Err(format!("Invalid number '{}'", word)),
+                }
+            } else {
+                Token::Name(word.to_string())
+            };
+            tokens.push(token);
+            rest = &rest[len..];
+        } else {
+            let Some(symbol) = SYMBOLS.iter().find(|s| rest.starts_with(**s)) else {
+                return Err(format!("Unexpected '{}'", c));
+            };
+            tokens.push(Token::Symbol(symbol));
+            rest = &rest[symbol.len()..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+// Binding power of each binary operator, higher binds tighter
+fn binary_op(symbol: &str) -> Option<(BinaryOp, u8)> {
+    let op = match symbol {
+        "||" => (BinaryOp::Or, 1),
+        "&&" => (BinaryOp::And, 2),
+        "|" => (BinaryOp::BitOr, 3),
+        "^" => (BinaryOp::BitXor, 4),
+        "&" => (BinaryOp::BitAnd, 5),
+        "==" => (BinaryOp::Eq, 6),
+        "!=" => (BinaryOp::Ne, 6),
+        "<" => (BinaryOp::Lt, 6),
+        "<=" => (BinaryOp::Le, 6),
+        ">" => (BinaryOp::Gt, 6),
+        ">=" => (BinaryOp::Ge, 6),
+        "+" => (BinaryOp::Add, 7),
+        "-" => (BinaryOp::Sub, 7),
+        _ => return None,
+    };
+    Some(op)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    // Precedence climbing, parses operators binding tighter than min_power
+    fn expression(&mut self, min_power: u8) -> Result<Expr, String> {
+        let mut left = self.unary()?;
+        while let Some(Token::Symbol(symbol)) = self.tokens.get(self.pos) {
+            let Some((op, power)) = binary_op(symbol) else {
+                break;
+            };
+            if power <= min_power {
+                break;
+            }
+            self.pos += 1;
+            let right = self.expression(power)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        let token = self.next()?;
+        match token {
+            Token::Number(n) => Ok(Expr::Number(n)),
+            Token::Name(name) => match operand_from_name(&name) {
+                Some(operand) => Ok(Expr::Operand(operand)),
+                None => Err(format!("Unknown register or flag '{}'", name)),
+            },
+            Token::Symbol("!") => Ok(Expr::Unary(UnaryOp::Not, Box::new(self.unary()?))),
+            Token::Symbol("-") => Ok(Expr::Unary(UnaryOp::Neg, Box::new(self.unary()?))),
+            Token::Symbol("(") => {
+                let expr = self.expression(0)?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Token::Symbol("[") => {
+                let expr = self.expression(0)?;
+                self.expect("]")?;
+                Ok(Expr::Memory(Box::new(expr)))
+            }
+            Token::Symbol(symbol) => Err(format!("Unexpected '{}'", symbol)),
+        }
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| "Unexpected end of expression".to_string())?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), String> {
+        match self.next()? {
+            Token::Symbol(s) if s == symbol => Ok(()),
+            token => Err(format!("Expected '{}' but found '{}'", symbol, token)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(text: &str) -> i64 {
+        let mut cpu = I8080::new();
+        cpu.registers.A = 0x10;
+        cpu.registers.H = 0x12;
+        cpu.registers.L = 0x34;
+        cpu.flags.Z = true;
+        cpu.memory[0x1234] = 7;
+        Expr::parse(text).unwrap().eval(&cpu)
+    }
+
+    #[test]
+    fn arithmetic_binds_tighter_than_comparison() {
+        assert_eq!(eval("1 + 2 == 3"), 1);
+        assert_eq!(eval("10 - 3 - 2"), 5);
+        assert_eq!(eval("-2 + 5"), 3);
+    }
+
+    #[test]
+    fn bitwise_operators_bind_in_c_order() {
+        // & before ^ before |
+        assert_eq!(eval("1 | 6 & 3"), 3);
+        assert_eq!(eval("1 ^ 3 & 1"), 0);
+        assert_eq!(eval("4 | 1 ^ 1"), 4);
+        // Comparison before &
+        assert_eq!(eval("2 & 1 == 1"), 0);
+    }
+
+    #[test]
+    fn logical_operators_bind_loosest() {
+        assert_eq!(eval("0 && 1 || 1"), 1);
+        assert_eq!(eval("1 || 1 && 0"), 1);
+        assert_eq!(eval("(1 || 1) && 0"), 0);
+        assert_eq!(eval("!0 && !(1 == 2)"), 1);
+    }
+
+    #[test]
+    fn reads_registers_flags_and_memory() {
+        assert_eq!(eval("A == 0x10 && Z"), 1);
+        assert_eq!(eval("HL"), 0x1234);
+        assert_eq!(eval("[HL] > 5"), 1);
+        assert_eq!(eval("[HL + 1]"), 0);
+        assert_eq!(eval("CY"), 0);
+    }
+
+    #[test]
+    fn reports_errors() {
+        let cases = [
+            ("", "Unexpected end of expression"),
+            ("A ==", "Unexpected end of expression"),
+            ("(A == 1", "Unexpected end of expression"),
+            ("[HL) ", "Expected ']' but found ')'"),
+            ("A 1", "Unexpected '1'"),
+            ("Q == 1", "Unknown register or flag 'Q'"),
+            ("0xzz", "Invalid number '0xzz'"),
+            ("A = 1", "Unexpected '='"),
+            ("== 1", "Unexpected '=='"),
+        ];
+        for (text, message) in cases {
+            assert_eq!(Expr::parse(text).unwrap_err(), message, "{:?}", text);
+        }
+    }
+}
@@ -0,0 +1,510 @@
+// GDB remote serial protocol server, so gdb and IDE front-ends can attach
+// to the emulator over a local TCP socket.
+//
+// Software breakpoints, write, read and access watchpoints and, when
+// history is enabled, reverse stepping and continuing are supported.
+// Registers are exposed as a, f, b, c, d, e, h, l, sp and pc, in that
+// order, as described by the target description served through qXfer. 16
+// bit registers are sent little endian like everything else in the
+// protocol.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::checksum::to_hex;
+use crate::cpu::{StatusFlags, MEMORY_SIZE};
+use crate::debugger::{Breakpoint, Debugger, Stop};
+use crate::utils::decode_hex;
+use crate::watchpoints::{WatchKind, Watchpoint};
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.intel8080.core">
+    <reg name="a" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="f" bitsize="8" type="uint8"/>
+    <reg name="b" bitsize="8" type="uint8"/>
+    <reg name="c" bitsize="8" type="uint8"/>
+    <reg name="d" bitsize="8" type="uint8"/>
+    <reg name="e" bitsize="8" type="uint8"/>
+    <reg name="h" bitsize="8" type="uint8"/>
+    <reg name="l" bitsize="8" type="uint8"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+// Bytes in the g packet
+const REGISTERS_SIZE: usize = 12;
+
+// Instructions run between checks for an interrupt from the client
+const POLL_INTERVAL: u64 = 10_000;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+// Waits for a single client on 127.0.0.1:port and serves it until it
+// detaches, kills the target or disconnects
+pub fn serve(debugger: &mut Debugger, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    eprintln!("Waiting for gdb on 127.0.0.1:{}", listener.local_addr()?.port());
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    Session { debugger, stream }.run()
+}
+
+struct Session<'a> {
+    debugger: &'a mut Debugger,
+    stream: TcpStream,
+}
+
+impl Session<'_> {
+    fn run(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            let reply = match packet.as_str() {
+                "D" => {
+                    self.send("OK")?;
+                    return Ok(());
+                }
+                "k" => return Ok(()),
+                _ => self.handle(&packet)?,
+            };
+            self.send(&reply)?;
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, packet: &str) -> io::Result<String> {
+        if packet.is_empty() || !packet.is_ascii() {
+            return Ok(String::new());
+        }
+        let (command, args) = packet.split_at(1);
+        let reply = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => to_hex(&self.read_registers()),
+            "G" => match decode_hex(args) {
+                Some(bytes) if bytes.len() == REGISTERS_SIZE => {
+                    self.write_registers(&bytes);
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(n) if n < 10 => {
+                    let registers = self.read_registers();
+                    let (start, len) = register_bytes(n);
+                    to_hex(&registers[start..start + len])
+                }
+                _ => "E01".to_string(),
+            },
+            "P" => self.write_register(args).unwrap_or_else(|| "E01".to_string()),
+            "m" => self.read_memory(args).unwrap_or_else(|| "E01".to_string()),
+            "M" => self.write_memory(args).unwrap_or_else(|| "E01".to_string()),
+            "s" => {
+                let stop = self.debugger.step(1);
+                stop_reply(&stop)
+            }
+            "c" => self.resume()?,
+            // Reverse execution, needs history to be enabled
+            "b" if args == "s" => stop_reply(&self.debugger.step_back(1)),
+            "b" if args == "c" => stop_reply(&self.debugger.reverse_cont()),
+            "Z" | "z" => self
+                .breakpoint(command == "Z", args)
+                .unwrap_or_else(|| "E01".to_string()),
+            "H" => "OK".to_string(),
+            "q" => self.query(args),
+            // Anything else is unsupported, which gdb expects an empty reply for
+            _ => String::new(),
+        };
+        Ok(reply)
+    }
+
+    fn query(&self, args: &str) -> String {
+        if args.starts_with("Supported") {
+            return "PacketSize=4000;qXfer:features:read+;ReverseStep+;ReverseContinue+".to_string();
+        }
+        if args == "Attached" {
+            return "1".to_string();
+        }
+        if let Some(rest) = args.strip_prefix("Xfer:features:read:target.xml:") {
+            let Some((offset, len)) = rest.split_once(',') else {
+                return "E01".to_string();
+            };
+            let offset = usize::from_str_radix(offset, 16);
+            let len = usize::from_str_radix(len, 16);
+            let (Ok(offset), Ok(len)) = (offset, len) else {
+                return "E01".to_string();
+            };
+            let start = offset.min(TARGET_XML.len());
+            let end = (start + len).min(TARGET_XML.len());
+            let marker = if end == TARGET_XML.len() { 'l' } else { 'm' };
+            return format!("{}{}", marker, escape(&TARGET_XML[start..end]));
+        }
+        String::new()
+    }
+
+    // Runs until a stop, checking now and then whether the client sent ^C.
+    // Only the first chunk steps off a breakpoint, later ones start where
+    // the last left off and may start on one.
+    fn resume(&mut self) -> io::Result<String> {
+        let mut stop = self.debugger.step(POLL_INTERVAL);
+        loop {
+            if !matches!(stop, Stop::Done) {
+                return Ok(stop_reply(&stop));
+            }
+            if self.interrupted()? {
+                return Ok(format!("S{:02x}", SIGINT));
+            }
+            stop = self.debugger.step_on(POLL_INTERVAL);
+        }
+    }
+
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut byte = [0];
+        let result = self.stream.read(&mut byte);
+        self.stream.set_nonblocking(false)?;
+        match result {
+            Ok(1) => Ok(byte[0] == 0x03),
+            Ok(_) => Err(io::Error::new(ErrorKind::UnexpectedEof, "client disconnected")),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn read_registers(&self) -> [u8; REGISTERS_SIZE] {
+        let cpu = &self.debugger.system.cpu;
+        let r = &cpu.registers;
+        let [sp_low, sp_high] = r.SP.to_le_bytes();
+        let [pc_low, pc_high] = r.PC.to_le_bytes();
+        [r.A, cpu.flags.to_byte(), r.B, r.C, r.D, r.E, r.H, r.L, sp_low, sp_high, pc_low, pc_high]
+    }
+
+    fn write_registers(&mut self, bytes: &[u8]) {
+        let cpu = &mut self.debugger.system.cpu;
+        let r = &mut cpu.registers;
+        r.A = bytes[0];
+        r.B = bytes[2];
+        r.C = bytes[3];
+        r.D = bytes[4];
+        r.E = bytes[5];
+        r.H = bytes[6];
+        r.L = bytes[7];
+        r.SP = u16::from_le_bytes([bytes[8], bytes[9]]);
+        r.PC = u16::from_le_bytes([bytes[10], bytes[11]]);
+        cpu.flags = StatusFlags::from_byte(bytes[1]);
+    }
+
+    // P<n>=<value>
+    fn write_register(&mut self, args: &str) -> Option<String> {
+        let (n, value) = args.split_once('=')?;
+        let n = usize::from_str_radix(n, 16).ok().filter(|n| *n < 10)?;
+        let value = decode_hex(value)?;
+        let (start, len) = register_bytes(n);
+        if value.len() != len {
+            return None;
+        }
+        let mut registers = self.read_registers();
+        registers[start..start + len].copy_from_slice(&value);
+        self.write_registers(&registers);
+        Some("OK".to_string())
+    }
+
+    // m<addr>,<len>
+    fn read_memory(&self, args: &str) -> Option<String> {
+        let (addr, len) = parse_addr_len(args)?;
+        let memory = &self.debugger.system.cpu.memory;
+        let bytes: Vec<u8> = (0..len).map(|i| memory[(addr + i) % MEMORY_SIZE]).collect();
+        Some(to_hex(&bytes))
+    }
+
+    // M<addr>,<len>:<data>
+    fn write_memory(&mut self, args: &str) -> Option<String> {
+        let (range, data) = args.split_once(':')?;
+        let (addr, len) = parse_addr_len(range)?;
+        let data = decode_hex(data).filter(|data| data.len() == len)?;
+        let memory = &mut self.debugger.system.cpu.memory;
+        for (i, byte) in data.iter().enumerate() {
+            memory[(addr + i) % MEMORY_SIZE] = *byte;
+        }
+        Some("OK".to_string())
+    }
+
+    // Z<type>,<addr>,<kind> and z<type>,<addr>,<kind>. Type 0 is a software
+    // breakpoint, 2-4 are write, read and access watchpoints of kind bytes.
+    fn breakpoint(&mut self, insert: bool, args: &str) -> Option<String> {
+        let mut fields = args.split(',');
+        let watch_kind = match fields.next()? {
+            "0" => None,
+            "2" => Some(WatchKind::Write),
+            "3" => Some(WatchKind::Read),
+            "4" => Some(WatchKind::Access),
+            _ => return Some(String::new()),
+        };
+        let addr = usize::from_str_radix(fields.next()?, 16)
+            .ok()
+            .filter(|addr| *addr < MEMORY_SIZE)? as u16;
+        let len = usize::from_str_radix(fields.next()?, 16).ok()?;
+
+        let Some(kind) = watch_kind else {
+            if insert {
+                self.debugger.breakpoints.insert(addr, Breakpoint::new(None));
+            } else {
+                self.debugger.breakpoints.remove(&addr);
+            }
+            return Some("OK".to_string());
+        };
+
+        let end = (addr as usize + len.max(1) - 1).min(MEMORY_SIZE - 1) as u16;
+        let watchpoint = Watchpoint { start: addr, end, kind };
+        let watchpoints = &mut self.debugger.system.cpu.watchpoints;
+        if insert {
+            watchpoints.push(watchpoint);
+        } else if let Some(pos) = watchpoints.iter().position(|w| *w == watchpoint) {
+            watchpoints.remove(pos);
+        }
+        Some("OK".to_string())
+    }
+
+    // Returns None once the client has gone away
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            let mut byte = [0];
+            // Skip acks and stray interrupts until a packet starts
+            loop {
+                if self.stream.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+
+            let mut data = Vec::new();
+            loop {
+                if self.stream.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'#' {
+                    break;
+                }
+                data.push(byte[0]);
+            }
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum)?;
+
+            let expected = data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+            let received = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|text| u8::from_str_radix(text, 16).ok());
+            if received == Some(expected) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |acc, b| acc.wrapping_add(b));
+        write!(self.stream, "${}#{:02x}", data, checksum)?;
+        self.stream.flush()
+    }
+}
+
+fn stop_reply(stop: &Stop) -> String {
+    match stop {
+        Stop::Halted => "W00".to_string(),
+        Stop::Watchpoint(hits) => {
+            // Reported against the first watchpoint hit, named for the
+            // kind of watchpoint gdb set rather than the access made
+            let hit = &hits[0];
+            let name = match hit.kind {
+                WatchKind::Read => "rwatch",
+                WatchKind::Write => "watch",
+                WatchKind::Access => "awatch",
+            };
+            format!("T{:02x}{}:{:04x};", SIGTRAP, name, hit.address)
+        }
+        Stop::Error(_) => format!("S{:02x}", SIGILL),
+        Stop::Done | Stop::Breakpoint(_) | Stop::BadReturn(_) | Stop::SelfModifying(_)
+        | Stop::UninitialisedRead(_) | Stop::StackGuard(_)
+        | Stop::RomWrite(_) => format!("S{:02x}", SIGTRAP),
+        Stop::HistoryStart => format!("T{:02x}replaylog:begin;", SIGTRAP),
+    }
+}
+
+// Offset and size of register n within the g packet
+fn register_bytes(n: usize) -> (usize, usize) {
+    match n {
+        0..=7 => (n, 1),
+        _ => (8 + (n - 8) * 2, 2),
+    }
+}
+
+fn parse_addr_len(text: &str) -> Option<(usize, usize)> {
+    let (addr, len) = text.split_once(',')?;
+    let addr = usize::from_str_radix(addr, 16).ok().filter(|addr| *addr < MEMORY_SIZE)?;
+    let len = usize::from_str_radix(len, 16).ok().filter(|len| *len <= MEMORY_SIZE)?;
+    Some((addr, len))
+}
+
+// Characters with a meaning in the framing are sent as '}' then the
+// character xor 0x20
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '#' | '$' | '}' | '*') {
+            escaped.push('}');
+            escaped.push((c as u8 ^ 0x20) as char);
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::{MachineType, System};
+    use std::thread;
+
+    // The client's side of the framing, checking the stub's checksums
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn send(&mut self, packet: &str) {
+            let checksum = packet.bytes().fold(0u8, |acc, b| acc.wrapping_add(b));
+            write!(self.stream, "${}#{:02x}", packet, checksum).unwrap();
+            assert_eq!(self.byte(), b'+', "ack for {}", packet);
+        }
+
+        fn reply(&mut self) -> String {
+            assert_eq!(self.byte(), b'$');
+            let mut data = Vec::new();
+            loop {
+                match self.byte() {
+                    b'#' => break,
+                    byte => data.push(byte),
+                }
+            }
+            let checksum = [self.byte(), self.byte()];
+            let checksum = u8::from_str_radix(std::str::from_utf8(&checksum).unwrap(), 16).unwrap();
+            let expected = data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+            assert_eq!(checksum, expected, "checksum of {:?}", String::from_utf8_lossy(&data));
+            self.stream.write_all(b"+").unwrap();
+            String::from_utf8(data).unwrap()
+        }
+
+        fn exchange(&mut self, packet: &str) -> String {
+            self.send(packet);
+            self.reply()
+        }
+
+        fn byte(&mut self) -> u8 {
+            let mut byte = [0];
+            self.stream.read_exact(&mut byte).unwrap();
+            byte[0]
+        }
+    }
+
+    // Serves a debugger running program at 0 to a client on another thread
+    fn session(program: &[u8], script: impl FnOnce(&mut Client) + Send + 'static) {
+        let mut debugger = Debugger::new(System::new(MachineType::Bare));
+        debugger.system.cpu.memory[..program.len()].copy_from_slice(program);
+
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = thread::spawn(move || {
+            let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            stream.set_nodelay(true).unwrap();
+            let mut client = Client { stream };
+            script(&mut client);
+            client.send("k");
+        });
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nodelay(true).unwrap();
+        Session { debugger: &mut debugger, stream }.run().unwrap();
+        client.join().unwrap();
+    }
+
+    // LXI SP,0100; MVI A,42; NOP; HLT
+    const PROGRAM: &[u8] = &[0x31, 0x00, 0x01, 0x3e, 0x42, 0x00, 0x76];
+
+    #[test]
+    fn serves_registers_memory_and_execution() {
+        session(PROGRAM, |client| {
+            assert_eq!(client.exchange("?"), "S05");
+            assert_eq!(client.exchange("g"), "000200000000000000000000");
+            assert_eq!(client.exchange("m0,3"), "310001");
+            assert_eq!(client.exchange("m5,2"), "0076");
+
+            assert_eq!(client.exchange("s"), "S05");
+            // a f b c d e h l, then SP and PC little endian
+            assert_eq!(client.exchange("g"), "000200000000000000010300");
+
+            assert_eq!(client.exchange("Z0,5,1"), "OK");
+            assert_eq!(client.exchange("c"), "S05");
+            assert_eq!(client.exchange("p0"), "42");
+            assert_eq!(client.exchange("p9"), "0500");
+
+            assert_eq!(client.exchange("z0,5,1"), "OK");
+            assert_eq!(client.exchange("s"), "S05");
+            assert_eq!(client.exchange("p9"), "0600");
+            assert_eq!(client.exchange("c"), "W00");
+        });
+    }
+
+    #[test]
+    fn stops_at_breakpoints_on_a_poll_boundary() {
+        // 0000: NOP; JMP 0000, a loop of two instructions that divides
+        // POLL_INTERVAL, so each chunk after the first starts at 0000
+        assert_eq!(POLL_INTERVAL % 2, 0);
+        session(&[0x00, 0xc3, 0x00, 0x00], |client| {
+            assert_eq!(client.exchange("s"), "S05");
+            assert_eq!(client.exchange("s"), "S05");
+            assert_eq!(client.exchange("p9"), "0000");
+            assert_eq!(client.exchange("Z0,0,1"), "OK");
+            // Leaves 0000, and is back there at the end of the chunk
+            assert_eq!(client.exchange("c"), "S05");
+            assert_eq!(client.exchange("p9"), "0000");
+            // Single steps in between don't stop at it either
+            assert_eq!(client.exchange("s"), "S05");
+            assert_eq!(client.exchange("c"), "S05");
+            assert_eq!(client.exchange("p9"), "0000");
+        });
+    }
+
+    #[test]
+    fn names_watchpoint_stops_by_kind() {
+        // STA 0100; LDA 0100; HLT
+        let program = [0x32, 0x00, 0x01, 0x3a, 0x00, 0x01, 0x76];
+        session(&program, |client| {
+            assert_eq!(client.exchange("Z4,100,1"), "OK");
+            assert_eq!(client.exchange("c"), "T05awatch:0100;");
+            assert_eq!(client.exchange("c"), "T05awatch:0100;");
+            assert_eq!(client.exchange("z4,100,1"), "OK");
+            assert_eq!(client.exchange("G000200000000000000000000"), "OK");
+            assert_eq!(client.exchange("Z2,100,1"), "OK");
+            assert_eq!(client.exchange("Z3,100,1"), "OK");
+            assert_eq!(client.exchange("c"), "T05watch:0100;");
+            assert_eq!(client.exchange("c"), "T05rwatch:0100;");
+        });
+    }
+
+    #[test]
+    fn asks_for_a_packet_again_when_its_checksum_is_wrong() {
+        session(PROGRAM, |client| {
+            client.stream.write_all(b"$m0,3#00").unwrap();
+            assert_eq!(client.byte(), b'-');
+            assert_eq!(client.exchange("m0,3"), "310001");
+            assert_eq!(client.exchange("m10000,1"), "E01");
+            assert_eq!(client.exchange("vMustReplyEmpty"), "");
+        });
+    }
+}
@@ -0,0 +1,192 @@
+// Memory heatmap: a 256x256 image with one pixel per address, the low byte
+// across and the high byte down. Writes show in red, reads in green and
+// execution (opcode and operand fetches) in blue, each channel scaled
+// logarithmically against its busiest address so rarely touched bytes
+// still show.
+//
+// Images are written as binary PPM, or as PNG when the file name ends in
+// .png. The PNG is uncompressed, which keeps the encoder to a few lines.
+
+use std::path::Path;
+
+use crate::checksum::{adler32, crc32};
+use crate::cpu::MEMORY_SIZE;
+use crate::watchpoints::Access;
+
+const SIDE: usize = 256;
+
+pub struct Heatmap {
+    reads: Vec<u64>,
+    writes: Vec<u64>,
+    executes: Vec<u64>,
+}
+
+impl Default for Heatmap {
+    fn default() -> Self {
+        Heatmap {
+            reads: vec![0; MEMORY_SIZE],
+            writes: vec![0; MEMORY_SIZE],
+            executes: vec![0; MEMORY_SIZE],
+        }
+    }
+}
+
+impl Heatmap {
+    pub fn record_access(&mut self, address: u16, access: Access) {
+        match access {
+            Access::Read => self.reads[address as usize] += 1,
+            Access::Write => self.writes[address as usize] += 1,
+        }
+    }
+
+    pub fn record_fetch(&mut self, address: u16) {
+        self.executes[address as usize] += 1;
+    }
+
+    // RGB bytes, row by row from address 0
+    pub fn pixels(&self) -> Vec<u8> {
+        let red = scale(&self.writes);
+        let green = scale(&self.reads);
+        let blue = scale(&self.executes);
+        (0..MEMORY_SIZE).flat_map(|a| [red[a], green[a], blue[a]]).collect()
+    }
+
+    // Image in the format the path's extension asks for
+    pub fn encode(&self, path: &Path) -> Vec<u8> {
+        let is_png = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("png"));
+        if is_png {
+            png(&self.pixels())
+        } else {
+            ppm(&self.pixels())
+        }
+    }
+}
+
+// Maps counts to 0-255, log scaled so the busiest count is 255 and any
+// non-zero count is visible
+fn scale(counts: &[u64]) -> Vec<u8> {
+    let max = counts.iter().copied().max().unwrap_or(0);
+    if max == 0 {
+        return vec![0; counts.len()];
+    }
+    let top = (max as f64).ln_1p();
+    counts
+        .iter()
+        .map(|&count| match count {
+            0 => 0,
+            _ => (64.0 + 191.0 * (count as f64).ln_1p() / top) as u8,
+        })
+        .collect()
+}
+
+fn ppm(pixels: &[u8]) -> Vec<u8> {
+    let mut image = format!("P6\n{} {}\n255\n", SIDE, SIDE).into_bytes();
+    image.extend_from_slice(pixels);
+    image
+}
+
+fn png(pixels: &[u8]) -> Vec<u8> {
+    let mut image = vec![0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+
+    // Width, height, 8 bits per channel, RGB, default compression, filter
+    // and no interlacing
+    let mut header = Vec::new();
+    header.extend_from_slice(&(SIDE as u32).to_be_bytes());
+    header.extend_from_slice(&(SIDE as u32).to_be_bytes());
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    png_chunk(&mut image, b"IHDR", &header);
+
+    // Each row starts with filter type 0, none
+    let mut raw = Vec::with_capacity(SIDE * (1 + SIDE * 3));
+    for row in pixels.chunks(SIDE * 3) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    png_chunk(&mut image, b"IDAT", &zlib_stored(&raw));
+    png_chunk(&mut image, b"IEND", &[]);
+    image
+}
+
+fn png_chunk(image: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    image.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = image.len();
+    image.extend_from_slice(kind);
+    image.extend_from_slice(data);
+    let crc = crc32(&image[start..]);
+    image.extend_from_slice(&crc.to_be_bytes());
+}
+
+// zlib stream holding data in uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    const BLOCK: usize = 0xffff;
+    let mut stream = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = data.chunks(BLOCK).collect();
+    for (i, block) in blocks.iter().enumerate() {
+        let last = i + 1 == blocks.len();
+        stream.push(last as u8);
+        let len = block.len() as u16;
+        stream.extend_from_slice(&len.to_le_bytes());
+        stream.extend_from_slice(&(!len).to_le_bytes());
+        stream.extend_from_slice(block);
+    }
+    stream.extend_from_slice(&adler32(data).to_be_bytes());
+    stream
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heatmap() -> Heatmap {
+        let mut heatmap = Heatmap::default();
+        heatmap.record_fetch(0x0102);
+        heatmap.record_access(0x0102, Access::Read);
+        heatmap.record_access(0xffff, Access::Write);
+        heatmap
+    }
+
+    #[test]
+    fn colours_each_address_by_use() {
+        let pixels = heatmap().pixels();
+        assert_eq!(pixels.len(), MEMORY_SIZE * 3);
+        let pixel = |address: usize| &pixels[address * 3..address * 3 + 3];
+        assert_eq!(pixel(0x0102), [0, 255, 255]);
+        assert_eq!(pixel(0xffff), [255, 0, 0]);
+        assert_eq!(pixel(0x0000), [0, 0, 0]);
+    }
+
+    #[test]
+    fn writes_a_ppm() {
+        let image = heatmap().encode(Path::new("heat.ppm"));
+        let header = b"P6\n256 256\n255\n";
+        assert_eq!(&image[..header.len()], header);
+        assert_eq!(image.len(), header.len() + SIDE * SIDE * 3);
+    }
+
+    #[test]
+    fn writes_a_png_with_valid_chunks() {
+        let image = heatmap().encode(Path::new("heat.PNG"));
+        assert_eq!(image[..8], [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a]);
+
+        let mut chunks = Vec::new();
+        let mut at = 8;
+        while at < image.len() {
+            let len = u32::from_be_bytes(image[at..at + 4].try_into().unwrap()) as usize;
+            let body = &image[at + 4..at + 8 + len];
+            let crc = u32::from_be_bytes(image[at + 8 + len..at + 12 + len].try_into().unwrap());
+            assert_eq!(crc, crc32(body));
+            chunks.push((body[..4].to_vec(), len, crc));
+            at += 12 + len;
+        }
+        assert_eq!(at, image.len());
+        let kinds: Vec<&[u8]> = chunks.iter().map(|(kind, _, _)| kind.as_slice()).collect();
+        assert_eq!(kinds, [&b"IHDR"[..], b"IDAT", b"IEND"]);
+
+        // 256 by 256, 8 bit RGB, with the CRCs any PNG of that shape has
+        assert_eq!(image[16..29], [0, 0, 1, 0, 0, 0, 1, 0, 8, 2, 0, 0, 0]);
+        assert_eq!((chunks[0].1, chunks[0].2), (13, 0xd310_3f31));
+        assert_eq!((chunks[2].1, chunks[2].2), (0, 0xae42_6082));
+    }
+}
@@ -0,0 +1,200 @@
+// Execution history for stepping backwards. Each entry holds the CPU state
+// from before an instruction and the bytes it overwrote, so undoing an
+// instruction is a matter of putting them back.
+//
+// Only the CPU, its shadow call stack and the machine's interrupt timing are
+// rewound: state kept by devices, such as the invaders shift register, stays
+// as it is.
+
+use std::collections::VecDeque;
+
+use crate::callstack::FrameChange;
+use crate::cpu::{Registers, StatusFlags};
+use crate::machine::System;
+
+// Instructions kept when no size is given
+pub const DEFAULT_CAPACITY: usize = 100_000;
+
+struct Entry {
+    registers: Registers,
+    flags: StatusFlags,
+    halted: bool,
+    interrupts_enabled: bool,
+    cycles: u64,
+    next_interrupt: u64,
+    next_vector: u8,
+    // Address and old value of each byte written, in the order written
+    writes: Vec<(u16, u8)>,
+    // Frames pushed and popped on the shadow call stack, in order
+    frames: Vec<FrameChange>,
+}
+
+pub struct History {
+    entries: VecDeque<Entry>,
+    // Oldest entries are dropped beyond this many
+    capacity: usize,
+    pending: Option<Entry>,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> History {
+        History {
+            entries: VecDeque::new(),
+            capacity: capacity.max(1),
+            pending: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // Call before executing an instruction, starts logging its writes
+    pub fn begin(&mut self, system: &mut System) {
+        let cpu = &mut system.cpu;
+        self.pending = Some(Entry {
+            registers: cpu.registers.clone(),
+            flags: cpu.flags.clone(),
+            halted: cpu.halted,
+            interrupts_enabled: cpu.interrupts_enabled,
+            cycles: cpu.cycles,
+            next_interrupt: system.next_interrupt,
+            next_vector: system.next_vector,
+            writes: Vec::new(),
+            frames: Vec::new(),
+        });
+        cpu.write_log = Some(Vec::new());
+        if let Some(call_stack) = &mut cpu.call_stack {
+            call_stack.log = Some(Vec::new());
+        }
+    }
+
+    // Call after executing an instruction to record it
+    pub fn commit(&mut self, system: &mut System) {
+        let Some(mut entry) = self.pending.take() else {
+            return;
+        };
+        let cpu = &mut system.cpu;
+        entry.writes = cpu.write_log.take().unwrap_or_default();
+        if let Some(call_stack) = &mut cpu.call_stack {
+            entry.frames = call_stack.log.take().unwrap_or_default();
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    // Undoes the most recent instruction, false once history runs out
+    pub fn undo(&mut self, system: &mut System) -> bool {
+        let Some(entry) = self.entries.pop_back() else {
+            return false;
+        };
+        system.next_interrupt = entry.next_interrupt;
+        system.next_vector = entry.next_vector;
+        let cpu = &mut system.cpu;
+        for (addr, old) in entry.writes.iter().rev() {
+            cpu.memory[*addr as usize] = *old;
+        }
+        cpu.registers = entry.registers;
+        cpu.flags = entry.flags;
+        cpu.halted = entry.halted;
+        cpu.interrupts_enabled = entry.interrupts_enabled;
+        cpu.cycles = entry.cycles;
+        if let Some(call_stack) = &mut cpu.call_stack {
+            call_stack.undo(&entry.frames);
+        }
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::callstack::CallStack;
+    use crate::machine::MachineType;
+
+    // Runs an instruction the way the debugger does
+    fn step(history: &mut History, system: &mut System) {
+        history.begin(system);
+        system.step().unwrap();
+        history.commit(system);
+    }
+
+    #[test]
+    fn undo_rewinds_memory_registers_and_call_stack() {
+        let mut system = System::new(MachineType::Bare);
+        let cpu = &mut system.cpu;
+        cpu.call_stack = Some(CallStack::default());
+        // LXI SP,0100; CALL 0010; HLT; ...; 0010: RET
+        cpu.memory[..7].copy_from_slice(&[0x31, 0x00, 0x01, 0xcd, 0x10, 0x00, 0x76]);
+        cpu.memory[0x10] = 0xc9;
+        let mut history = History::new(10);
+
+        step(&mut history, &mut system);
+        step(&mut history, &mut system);
+        let cpu = &system.cpu;
+        assert_eq!(cpu.registers.PC, 0x10);
+        assert_eq!(cpu.memory[0xfe..0x100], [0x06, 0x00]);
+        assert_eq!(cpu.call_stack.as_ref().unwrap().frames.len(), 1);
+        step(&mut history, &mut system);
+        assert_eq!(system.cpu.call_stack.as_ref().unwrap().frames.len(), 0);
+
+        // Back into the subroutine, its frame should be there again
+        assert!(history.undo(&mut system));
+        assert_eq!(system.cpu.registers.PC, 0x10);
+        assert_eq!(system.cpu.call_stack.as_ref().unwrap().frames.len(), 1);
+        // Back before the call, and running forwards again returns cleanly
+        assert!(history.undo(&mut system));
+        assert_eq!(system.cpu.registers.PC, 0x03);
+        assert_eq!(system.cpu.memory[0xfe..0x100], [0x00, 0x00]);
+        assert_eq!(system.cpu.call_stack.as_ref().unwrap().frames.len(), 0);
+        step(&mut history, &mut system);
+        step(&mut history, &mut system);
+        let call_stack = system.cpu.call_stack.as_ref().unwrap();
+        assert!(call_stack.frames.is_empty());
+        assert!(call_stack.mismatches.is_empty());
+
+        assert_eq!(history.len(), 3);
+        assert!(history.undo(&mut system));
+        assert!(history.undo(&mut system));
+        assert!(history.undo(&mut system));
+        assert!(!history.undo(&mut system));
+        assert_eq!(system.cpu.registers.PC, 0);
+    }
+
+    #[test]
+    fn undo_rewinds_the_next_interrupt() {
+        let mut system = System::new(MachineType::Invaders);
+        system.cpu.call_stack = Some(CallStack::default());
+        // LXI SP,2400; EI; HLT
+        system.cpu.memory[..5].copy_from_slice(&[0x31, 0x00, 0x24, 0xfb, 0x76]);
+        let (due, vector) = (system.next_interrupt, system.next_vector);
+        let mut history = History::new(10);
+        while system.next_interrupt == due {
+            step(&mut history, &mut system);
+        }
+        assert_eq!(system.cpu.call_stack.as_ref().unwrap().frames.len(), 1);
+
+        // Back to waiting, with the interrupt still to come
+        assert!(history.undo(&mut system));
+        assert_eq!((system.next_interrupt, system.next_vector), (due, vector));
+        assert!(system.cpu.halted);
+        assert!(system.cpu.call_stack.as_ref().unwrap().frames.is_empty());
+        step(&mut history, &mut system);
+        assert_eq!(system.cpu.registers.PC, 0x0008);
+        assert_eq!(system.cpu.call_stack.as_ref().unwrap().frames.len(), 1);
+    }
+}
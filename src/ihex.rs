@@ -0,0 +1,222 @@
+// Intel HEX images, as produced by most 8080 assemblers and linkers
+//
+// Each line is a record of the form
+//   :LLAAAATT<data>CC
+// with a byte count, a 16 bit address, a record type, the data bytes and a
+// two's complement checksum over everything before it.
+
+use std::fmt::Write;
+
+use crate::cpu::MEMORY_SIZE;
+use crate::loader::{LoadError, RomSet, Segment};
+use crate::utils::decode_hex;
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const START_SEGMENT_ADDRESS: u8 = 0x03;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+const BYTES_PER_RECORD: usize = 16;
+
+// Parses an image into a ROM set, merging adjacent data records into a
+// single segment. Any start address record sets the entry point.
+pub fn parse(name: &str, text: &str) -> Result<RomSet, LoadError> {
+    let mut rom_set = RomSet::default();
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut seen_eof = false;
+
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let parse_error = |message: String| LoadError::Parse { line: line_number, message };
+
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if seen_eof {
+            return Err(parse_error("Record after end of file".to_string()));
+        }
+
+        let hex = line
+            .strip_prefix(':')
+            .ok_or_else(|| parse_error("Record does not start with ':'".to_string()))?;
+        let bytes = decode_hex(hex).ok_or_else(|| parse_error("Invalid hex digits".to_string()))?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(parse_error("Record length does not match byte count".to_string()));
+        }
+        let sum = bytes.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+        if sum != 0 {
+            let expected = checksum(&bytes[..bytes.len() - 1]);
+            return Err(parse_error(format!(
+                "Bad checksum 0x{:02x}, expected 0x{:02x}",
+                bytes[bytes.len() - 1],
+                expected
+            )));
+        }
+
+        let address = u16::from_be_bytes([bytes[1], bytes[2]]);
+        let record_type = bytes[3];
+        let data = &bytes[4..bytes.len() - 1];
+
+        match record_type {
+            DATA => {
+                if address as usize + data.len() > MEMORY_SIZE {
+                    return Err(parse_error(format!(
+                        "{} bytes at 0x{:04x} do not fit in memory",
+                        data.len(),
+                        address
+                    )));
+                }
+                match segments.last_mut() {
+                    Some(last) if last.address as usize + last.data.len() == address as usize => {
+                        last.data.extend_from_slice(data);
+                    }
+                    _ => segments.push(Segment {
+                        name: name.to_string(),
+                        address,
+                        data: data.to_vec(),
+                        read_only: false,
+                    }),
+                }
+            }
+            END_OF_FILE => seen_eof = true,
+            // Only the bottom 64K is addressable so any non-zero base is an error
+            EXTENDED_SEGMENT_ADDRESS | EXTENDED_LINEAR_ADDRESS => {
+                if data.iter().any(|b| *b != 0) {
+                    return Err(parse_error("Extended address beyond 64K".to_string()));
+                }
+            }
+            START_SEGMENT_ADDRESS | START_LINEAR_ADDRESS => {
+                if data.len() != 4 {
+                    return Err(parse_error("Start address record must hold 4 bytes".to_string()));
+                }
+                let entry = if record_type == START_SEGMENT_ADDRESS {
+                    let cs = u16::from_be_bytes([data[0], data[1]]) as usize;
+                    let ip = u16::from_be_bytes([data[2], data[3]]) as usize;
+                    (cs << 4) + ip
+                } else {
+                    u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize
+                };
+                if entry >= MEMORY_SIZE {
+                    return Err(parse_error(format!("Start address 0x{:x} beyond 64K", entry)));
+                }
+                rom_set.entry = Some(entry as u16);
+            }
+            _ => return Err(parse_error(format!("Unknown record type 0x{:02x}", record_type))),
+        }
+    }
+
+    if !seen_eof {
+        return Err(LoadError::Parse {
+            line: text.lines().count(),
+            message: "Missing end of file record".to_string(),
+        });
+    }
+
+    for segment in segments {
+        rom_set.push(segment)?;
+    }
+    Ok(rom_set)
+}
+
+// Writes memory[start..=end] as data records followed by an optional start
+// address and the end of file record
+pub fn write(memory: &[u8], start: u16, end: u16, entry: Option<u16>) -> String {
+    let mut out = String::new();
+
+    let region = &memory[start as usize..=end as usize];
+    for (i, chunk) in region.chunks(BYTES_PER_RECORD).enumerate() {
+        let address = start as usize + i * BYTES_PER_RECORD;
+        write_record(&mut out, address as u16, DATA, chunk);
+    }
+    if let Some(entry) = entry {
+        let [high, low] = entry.to_be_bytes();
+        write_record(&mut out, 0, START_SEGMENT_ADDRESS, &[0, 0, high, low]);
+    }
+    write_record(&mut out, 0, END_OF_FILE, &[]);
+
+    out
+}
+
+fn write_record(out: &mut String, address: u16, record_type: u8, data: &[u8]) {
+    let [high, low] = address.to_be_bytes();
+    let mut bytes = vec![data.len() as u8, high, low, record_type];
+    bytes.extend_from_slice(data);
+    bytes.push(checksum(&bytes));
+
+    out.push(':');
+    for byte in bytes {
+        write!(out, "{:02X}", byte).unwrap();
+    }
+    out.push('\n');
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)).wrapping_neg()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_data_start_and_end_records() {
+        let text = "\
+:0401000001020304F1
+:020104000506EE
+:0400000300000100F8
+:00000001FF
+";
+        let rom_set = parse("test.hex", text).unwrap();
+        assert_eq!(rom_set.segments.len(), 1);
+        assert_eq!(rom_set.segments[0].address, 0x0100);
+        assert_eq!(rom_set.segments[0].data, [1, 2, 3, 4, 5, 6]);
+        assert_eq!(rom_set.entry, Some(0x0100));
+    }
+
+    #[test]
+    fn bad_checksum_names_the_line() {
+        let text = ":0401000001020304F1\n:020104000506EF\n:00000001FF\n";
+        match parse("test.hex", text) {
+            Err(LoadError::Parse { line, message }) => {
+                assert_eq!(line, 2);
+                assert_eq!(message, "Bad checksum 0xef, expected 0xee");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_records() {
+        let cases = [
+            ("0401000001020304F1\n:00000001FF\n", 1, "Record does not start with ':'"),
+            (":0501000001020304F1\n:00000001FF\n", 1, "Record length does not match byte count"),
+            (":00000001FF\n:00000001FF\n", 2, "Record after end of file"),
+            (":0401000001020304F1\n", 1, "Missing end of file record"),
+        ];
+        for (text, expected_line, expected_message) in cases {
+            match parse("test.hex", text) {
+                Err(LoadError::Parse { line, message }) => {
+                    assert_eq!((line, message.as_str()), (expected_line, expected_message));
+                }
+                other => panic!("unexpected {:?} for {:?}", other, text),
+            }
+        }
+    }
+
+    #[test]
+    fn write_round_trips() {
+        let mut memory = vec![0; MEMORY_SIZE];
+        for (i, byte) in memory[0x200..0x223].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let text = write(&memory, 0x200, 0x222, Some(0x200));
+        assert_eq!(text.lines().count(), 5);
+        let rom_set = parse("round.hex", &text).unwrap();
+        assert_eq!(rom_set.segments[0].address, 0x200);
+        assert_eq!(rom_set.segments[0].data, &memory[0x200..0x223]);
+        assert_eq!(rom_set.entry, Some(0x200));
+    }
+}
@@ -0,0 +1,34 @@
+// Register and flag names follow the 8080 datasheet rather than snake case
+#![allow(non_snake_case)]
+
+pub mod callstack;
+pub mod checksum;
+pub mod coverage;
+pub mod cpu;
+pub mod debugger;
+pub mod devices;
+pub mod disassembler;
+pub mod expr;
+pub mod gdbstub;
+pub mod heatmap;
+pub mod history;
+pub mod ihex;
+pub mod loader;
+pub mod machine;
+pub mod monitor;
+pub mod postmortem;
+pub mod profiler;
+pub mod provenance;
+pub mod rom;
+pub mod romdb;
+pub mod savestate;
+pub mod selfmod;
+pub mod snapdiff;
+pub mod srec;
+pub mod stackguard;
+pub mod statistics;
+pub mod symbols;
+pub mod trace;
+pub mod uninit;
+pub mod utils;
+pub mod watchpoints;
@@ -0,0 +1,304 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cpu::MEMORY_SIZE;
+use crate::{ihex, srec};
+use crate::utils::parse_number;
+
+// A block of bytes to be placed in memory at a fixed address
+#[derive(Debug)]
+pub struct Segment {
+    pub name: String,
+    pub address: u16,
+    pub data: Vec<u8>,
+    // Set by the manifest's ro option, writes to it are refused
+    pub read_only: bool,
+}
+
+impl Segment {
+    // First and last address covered, None when empty
+    pub fn range(&self) -> Option<(u16, u16)> {
+        let last = self.address as usize + self.data.len();
+        (!self.data.is_empty()).then(|| (self.address, (last - 1) as u16))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RomSet {
+    pub segments: Vec<Segment>,
+    // Where execution should start, if the image says
+    pub entry: Option<u16>,
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io { path: PathBuf, message: String },
+    Parse { line: usize, message: String },
+    SizeMismatch { name: String, expected: usize, actual: usize },
+    OutOfRange { name: String, address: u16, len: usize },
+    Overlap { name: String, other: String, start: u16, end: u16 },
+    BadSpec { spec: String },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io { path, message } => {
+                write!(f, "Could not read {}: {}", path.display(), message)
+            }
+            LoadError::Parse { line, message } => write!(f, "Line {}: {}", line, message),
+            LoadError::SizeMismatch { name, expected, actual } => write!(
+                f,
+                "{} is {} bytes, expected {}",
+                name, actual, expected
+            ),
+            LoadError::OutOfRange { name, address, len } => write!(
+                f,
+                "{} ({} bytes at 0x{:04x}) does not fit in memory",
+                name, len, address
+            ),
+            LoadError::Overlap { name, other, start, end } => write!(
+                f,
+                "{} overlaps {} at 0x{:04x}-0x{:04x}",
+                name, other, start, end
+            ),
+            LoadError::BadSpec { spec } => {
+                write!(f, "Invalid load spec '{}', expected file@address", spec)
+            }
+        }
+    }
+}
+
+pub fn read_file(path: &Path) -> Result<Vec<u8>, LoadError> {
+    fs::read(path).map_err(|e| LoadError::Io {
+        path: path.to_path_buf(),
+        message: e.to_string(),
+    })
+}
+
+// Loads a single image, picking the format from the file extension:
+// .hex/.ihx are Intel HEX, .s19/.s28/.s37/.srec/.mot are S-records and
+// anything else is a raw binary placed at origin
+pub fn load_image(path: &Path, origin: u16) -> Result<RomSet, LoadError> {
+    let data = read_file(path)?;
+    let name = path.display().to_string();
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "hex" | "ihx" => {
+            let text = String::from_utf8_lossy(&data);
+            ihex::parse(&name, &text)
+        }
+        "s19" | "s28" | "s37" | "srec" | "mot" => {
+            let text = String::from_utf8_lossy(&data);
+            srec::parse(&name, &text)
+        }
+        _ => {
+            let mut rom_set = RomSet::default();
+            rom_set.push(Segment { name, address: origin, data, read_only: false })?;
+            Ok(rom_set)
+        }
+    }
+}
+
+// Loads a raw binary from a spec of the form file@address, the address
+// defaulting to 0 when left out
+pub fn load_raw(spec: &str) -> Result<RomSet, LoadError> {
+    let (file, address) = match spec.rsplit_once('@') {
+        Some((file, address)) => {
+            let address = parse_number(address)
+                .filter(|n| *n < MEMORY_SIZE)
+                .ok_or_else(|| LoadError::BadSpec { spec: spec.to_string() })?;
+            (file, address as u16)
+        }
+        None => (spec, 0),
+    };
+
+    let data = read_file(Path::new(file))?;
+    let mut rom_set = RomSet::default();
+    rom_set.push(Segment { name: file.to_string(), address, data, read_only: false })?;
+    Ok(rom_set)
+}
+
+impl RomSet {
+    // Reads a manifest where each line is
+    //   <file> <address> [size=<bytes>] [ro]
+    // Blank lines and anything after a '#' are ignored. File paths are
+    // relative to the directory containing the manifest.
+    pub fn from_manifest(path: &Path) -> Result<RomSet, LoadError> {
+        let text = fs::read_to_string(path).map_err(|e| LoadError::Io {
+            path: path.to_path_buf(),
+            message: e.to_string(),
+        })?;
+        let base = path.parent().unwrap_or(Path::new("."));
+
+        let mut rom_set = RomSet::default();
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let line = match line.find('#') {
+                Some(pos) => &line[..pos],
+                None => line,
+            };
+            let mut fields = line.split_whitespace();
+            let file = match fields.next() {
+                Some(file) => file,
+                None => continue,
+            };
+            let parse_error = |message: String| LoadError::Parse { line: line_number, message };
+
+            let address = match fields.next() {
+                Some(field) => parse_number(field)
+                    .filter(|n| *n < MEMORY_SIZE)
+                    .ok_or_else(|| parse_error(format!("Invalid address '{}'", field)))?,
+                None => return Err(parse_error(format!("Missing load address for {}", file))),
+            };
+
+            let mut size = None;
+            let mut read_only = false;
+            for field in fields {
+                if field == "ro" {
+                    read_only = true;
+                } else if let Some(value) = field.strip_prefix("size=") {
+                    size = Some(
+                        parse_number(value)
+                            .ok_or_else(|| parse_error(format!("Invalid size '{}'", value)))?,
+                    );
+                } else {
+                    return Err(parse_error(format!("Unknown option '{}'", field)));
+                }
+            }
+
+            let data = read_file(&base.join(file))?;
+            if let Some(expected) = size {
+                if expected != data.len() {
+                    return Err(LoadError::SizeMismatch {
+                        name: file.to_string(),
+                        expected,
+                        actual: data.len(),
+                    });
+                }
+            }
+
+            rom_set.push(Segment {
+                name: file.to_string(),
+                address: address as u16,
+                data,
+                read_only,
+            })?;
+        }
+
+        Ok(rom_set)
+    }
+
+    pub fn push(&mut self, segment: Segment) -> Result<(), LoadError> {
+        if segment.address as usize + segment.data.len() > MEMORY_SIZE {
+            return Err(LoadError::OutOfRange {
+                name: segment.name,
+                address: segment.address,
+                len: segment.data.len(),
+            });
+        }
+
+        let end = segment.address as usize + segment.data.len();
+        for other in &self.segments {
+            let other_end = other.address as usize + other.data.len();
+            let start = segment.address.max(other.address) as usize;
+            let overlap_end = end.min(other_end);
+            if start < overlap_end {
+                return Err(LoadError::Overlap {
+                    name: segment.name,
+                    other: other.name.clone(),
+                    start: start as u16,
+                    end: (overlap_end - 1) as u16,
+                });
+            }
+        }
+
+        self.segments.push(segment);
+        Ok(())
+    }
+
+    // Adds all of another set's segments, checking each for overlaps
+    pub fn extend(&mut self, other: RomSet) -> Result<(), LoadError> {
+        for segment in other.segments {
+            self.push(segment)?;
+        }
+        if other.entry.is_some() {
+            self.entry = other.entry;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes a manifest and the files it names to a fresh directory, then
+    // loads it
+    fn load(test: &str, manifest: &str, files: &[(&str, &[u8])]) -> Result<RomSet, LoadError> {
+        let name = format!("intel8080-loader-{}-{}", std::process::id(), test);
+        let dir = std::env::temp_dir().join(name);
+        fs::create_dir_all(&dir).unwrap();
+        for (name, data) in files {
+            fs::write(dir.join(name), data).unwrap();
+        }
+        fs::write(dir.join("set.txt"), manifest).unwrap();
+        let result = RomSet::from_manifest(&dir.join("set.txt"));
+        fs::remove_dir_all(&dir).unwrap();
+        result
+    }
+
+    const FILES: &[(&str, &[u8])] = &[("a.bin", &[1, 2, 3, 4]), ("b.bin", &[5, 6])];
+
+    #[test]
+    fn reads_manifests() {
+        let manifest = "# Test set\n\na.bin 0x1000 size=4 ro  # first chip\nb.bin 4100\n";
+        let rom_set = load("valid", manifest, FILES).unwrap();
+        let segments: Vec<(&str, u16, &[u8], bool)> = rom_set
+            .segments
+            .iter()
+            .map(|s| (s.name.as_str(), s.address, s.data.as_slice(), s.read_only))
+            .collect();
+        assert_eq!(
+            segments,
+            [("a.bin", 0x1000, &[1, 2, 3, 4][..], true), ("b.bin", 4100, &[5, 6][..], false)]
+        );
+        assert_eq!(rom_set.segments[0].range(), Some((0x1000, 0x1003)));
+    }
+
+    #[test]
+    fn rejects_size_mismatches() {
+        let error = load("size", "a.bin 0 size=0x800\n", FILES).unwrap_err();
+        assert!(matches!(error, LoadError::SizeMismatch { expected: 0x800, actual: 4, .. }));
+        assert_eq!(error.to_string(), "a.bin is 4 bytes, expected 2048");
+    }
+
+    #[test]
+    fn rejects_overlapping_segments() {
+        let error = load("overlap", "a.bin 0x10\nb.bin 0x13\n", FILES).unwrap_err();
+        assert!(matches!(error, LoadError::Overlap { start: 0x13, end: 0x13, .. }), "{}", error);
+    }
+
+    #[test]
+    fn rejects_addresses_outside_memory() {
+        let error = load("range", "a.bin 0xfffe\n", FILES).unwrap_err();
+        assert!(matches!(error, LoadError::OutOfRange { address: 0xfffe, len: 4, .. }), "{}", error);
+        let error = load("address", "a.bin 0\nb.bin 0x10000\n", FILES).unwrap_err();
+        assert_eq!(error.to_string(), "Line 2: Invalid address '0x10000'");
+        let error = load("missing", "a.bin\n", FILES).unwrap_err();
+        assert_eq!(error.to_string(), "Line 1: Missing load address for a.bin");
+    }
+
+    #[test]
+    fn rejects_unknown_options() {
+        let error = load("option", "a.bin 0 rw\n", FILES).unwrap_err();
+        assert_eq!(error.to_string(), "Line 1: Unknown option 'rw'");
+        let error = load("size-value", "a.bin 0 size=big\n", FILES).unwrap_err();
+        assert_eq!(error.to_string(), "Line 1: Invalid size 'big'");
+    }
+}
@@ -0,0 +1,271 @@
+// The systems an I8080 can be dropped into. Each decides where programs are
+// loaded, what is attached to the I/O ports and what happens around each
+// instruction, such as CP/M system calls or the video interrupts of the
+// Space Invaders cabinet.
+
+use std::io::{self, Write};
+
+use crate::coverage::Coverage;
+use crate::cpu::{CpuError, I8080, MEMORY_SIZE};
+use crate::devices::InvadersIo;
+use crate::heatmap::Heatmap;
+use crate::profiler::Profiler;
+use crate::provenance::Provenance;
+use crate::rom::{RomMap, RomWrites};
+use crate::romdb::{self, Machine};
+use crate::savestate::{Snapshot, StateError};
+use crate::selfmod::SelfModDetector;
+use crate::statistics::Statistics;
+
+// 2MHz clock, two interrupts per 60Hz frame
+const INVADERS_HALF_FRAME: u64 = 2_000_000 / 120;
+
+const BDOS: u16 = 0x0005;
+const WARM_BOOT: u16 = 0x0000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MachineType {
+    // Just the CPU and memory
+    Bare,
+    // Enough of CP/M's BDOS for console output from .COM files
+    Cpm,
+    Invaders,
+}
+
+impl MachineType {
+    pub fn from_name(name: &str) -> Option<MachineType> {
+        match name {
+            "bare" => Some(MachineType::Bare),
+            "cpm" => Some(MachineType::Cpm),
+            "invaders" => Some(MachineType::Invaders),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MachineType::Bare => "bare",
+            MachineType::Cpm => "cpm",
+            MachineType::Invaders => "invaders",
+        }
+    }
+
+    // Where raw images go when no address is given
+    pub fn load_address(&self) -> u16 {
+        match self {
+            MachineType::Cpm => 0x0100,
+            _ => 0x0000,
+        }
+    }
+
+    // Address range wired to ROM, inclusive
+    pub fn rom_range(&self) -> Option<(u16, u16)> {
+        match self {
+            MachineType::Invaders => Some((0x0000, 0x1fff)),
+            _ => None,
+        }
+    }
+
+    // What happens to writes to ROM unless told otherwise. Emulated
+    // hardware drops them quietly, elsewhere ROM only exists because the
+    // user asked for it so they are reported.
+    pub fn rom_writes(&self) -> RomWrites {
+        match self {
+            MachineType::Invaders => RomWrites::Ignore,
+            _ => RomWrites::Report,
+        }
+    }
+
+    // Known-good dumps to check loaded ROMs against
+    pub fn rom_table(&self) -> Option<&'static Machine> {
+        match self {
+            MachineType::Invaders => romdb::find_machine("invaders"),
+            _ => None,
+        }
+    }
+}
+
+pub struct System {
+    pub machine: MachineType,
+    pub cpu: I8080,
+    // Cycle the next machine interrupt is due at and its RST vector
+    pub next_interrupt: u64,
+    pub next_vector: u8,
+}
+
+impl System {
+    pub fn new(machine: MachineType) -> System {
+        let mut cpu = I8080::new();
+        if machine == MachineType::Invaders {
+            cpu.io = Box::new(InvadersIo::default());
+        }
+        if let Some((start, end)) = machine.rom_range() {
+            let mut rom = RomMap::new(machine.rom_writes());
+            rom.protect(start, end);
+            cpu.rom = Some(Box::new(rom));
+        }
+        System {
+            machine,
+            cpu,
+            next_interrupt: INVADERS_HALF_FRAME,
+            next_vector: 1,
+        }
+    }
+
+    // Points the CPU at a program's first instruction, setting up whatever
+    // the machine's firmware would normally leave behind
+    pub fn reset(&mut self, entry: u16) {
+        self.cpu.registers.PC = entry;
+        if self.machine == MachineType::Cpm {
+            // Programs may RET to the warm boot vector, leave its address
+            // on the stack just below the top of the TPA
+            self.cpu.memory[0xeffe] = 0x00;
+            self.cpu.memory[0xefff] = 0x00;
+            self.cpu.registers.SP = 0xeffe;
+            if let Some(uninit) = &mut self.cpu.uninit {
+                uninit.mark(0xeffe);
+                uninit.mark(0xefff);
+            }
+        }
+    }
+
+    pub fn save_state(&self) -> Snapshot {
+        let cpu = &self.cpu;
+        Snapshot {
+            machine: self.machine,
+            registers: cpu.registers.clone(),
+            flags: cpu.flags.clone(),
+            halted: cpu.halted,
+            interrupts_enabled: cpu.interrupts_enabled,
+            cycles: cpu.cycles,
+            next_interrupt: self.next_interrupt,
+            next_vector: self.next_vector,
+            device: cpu.io.save(),
+            memory: cpu.memory.to_vec(),
+        }
+    }
+
+    // Restores a snapshot taken of the same kind of machine. Memory is
+    // copied straight in, ROM included. Analysis hooks tracking memory or
+    // calls start over, as what they knew was about the state replaced.
+    pub fn load_state(&mut self, snapshot: &Snapshot) -> Result<(), StateError> {
+        if snapshot.memory.len() != MEMORY_SIZE {
+            return Err(StateError::Corrupt("wrong memory size".to_string()));
+        }
+        if snapshot.machine != self.machine {
+            return Err(StateError::WrongMachine {
+                expected: self.machine,
+                found: snapshot.machine,
+            });
+        }
+        if !self.cpu.io.restore(&snapshot.device) {
+            return Err(StateError::BadDevice);
+        }
+        let cpu = &mut self.cpu;
+        cpu.registers = snapshot.registers.clone();
+        cpu.flags = snapshot.flags.clone();
+        cpu.halted = snapshot.halted;
+        cpu.interrupts_enabled = snapshot.interrupts_enabled;
+        cpu.cycles = snapshot.cycles;
+        cpu.memory.copy_from_slice(&snapshot.memory);
+        self.next_interrupt = snapshot.next_interrupt;
+        self.next_vector = snapshot.next_vector;
+
+        // The snapshot doesn't say which bytes the program had written, so
+        // all of them count as initialised
+        if let Some(uninit) = &mut cpu.uninit {
+            uninit.mark_all();
+        }
+        if let Some(coverage) = &mut cpu.coverage {
+            **coverage = Coverage::default();
+        }
+        if let Some(self_mod) = &mut cpu.self_mod {
+            **self_mod = SelfModDetector::default();
+        }
+        if let Some(profiler) = &mut cpu.profiler {
+            **profiler = Profiler::default();
+        }
+        if let Some(statistics) = &mut cpu.statistics {
+            **statistics = Statistics::default();
+        }
+        if let Some(heatmap) = &mut cpu.heatmap {
+            **heatmap = Heatmap::default();
+        }
+        if let Some(provenance) = &mut cpu.provenance {
+            **provenance = Provenance::default();
+        }
+        if let Some(call_stack) = &mut cpu.call_stack {
+            call_stack.clear();
+        }
+        Ok(())
+    }
+
+    // Whether the program has stopped for good. The invaders cabinet halts
+    // between interrupts so only ever stops on an error.
+    pub fn finished(&self) -> bool {
+        self.cpu.halted && self.machine != MachineType::Invaders
+    }
+
+    // Executes one instruction along with any machine behaviour around it
+    pub fn step(&mut self) -> Result<(), CpuError> {
+        match self.machine {
+            MachineType::Bare => self.cpu.step(),
+            MachineType::Cpm => self.cpm_step(),
+            MachineType::Invaders => self.invaders_step(),
+        }
+    }
+
+    fn cpm_step(&mut self) -> Result<(), CpuError> {
+        match self.cpu.registers.PC {
+            WARM_BOOT => {
+                self.cpu.halted = true;
+                Ok(())
+            }
+            BDOS => {
+                self.bdos_call();
+                self.cpu.return_from_subroutine();
+                Ok(())
+            }
+            _ => self.cpu.step(),
+        }
+    }
+
+    fn bdos_call(&mut self) {
+        let registers = &self.cpu.registers;
+        let mut stdout = io::stdout();
+        match registers.C {
+            // Console output of E
+            2 => {
+                let _ = stdout.write_all(&[registers.E]);
+            }
+            // Print the string at DE up to a '$'
+            9 => {
+                let start = (registers.D as usize) << 8 | registers.E as usize;
+                let text = self.cpu.memory[start..]
+                    .split(|b| *b == b'$')
+                    .next()
+                    .unwrap_or_default();
+                let _ = stdout.write_all(text);
+            }
+            _ => {}
+        }
+        let _ = stdout.flush();
+    }
+
+    fn invaders_step(&mut self) -> Result<(), CpuError> {
+        if !self.cpu.halted {
+            self.cpu.step()?;
+        } else {
+            // Waiting for an interrupt, let time pass
+            self.cpu.cycles += 4;
+        }
+
+        if self.cpu.cycles >= self.next_interrupt {
+            self.next_interrupt += INVADERS_HALF_FRAME;
+            // Mid screen is RST 1, end of screen (vblank) is RST 2
+            self.cpu.interrupt(self.next_vector);
+            self.next_vector = if self.next_vector == 1 { 2 } else { 1 };
+        }
+        Ok(())
+    }
+}
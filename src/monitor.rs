@@ -0,0 +1,553 @@
+// Interactive monitor for poking at a running program. Reads commands from
+// any BufRead so it can be driven by a terminal or a script.
+
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+use crate::callstack::CallStack;
+use crate::cpu::{MEMORY_SIZE, I8080};
+use crate::debugger::{Breakpoint, Condition, Debugger, Stop};
+use crate::disassembler::{find_start_before, listing};
+use crate::history::{History, DEFAULT_CAPACITY};
+use crate::savestate::Snapshot;
+use crate::snapdiff::{diff, DEFAULT_PREVIEW};
+use crate::utils::hexdump;
+use crate::watchpoints::{WatchKind, Watchpoint};
+
+const HELP: &str = "\
+Numbers are hexadecimal, an empty line repeats the last command. Conditions
+are expressions such as 'A == 0x10 && Z' or '[HL] > 5' where numbers are
+decimal unless written 0x.., registers are A-L, BC DE HL SP PC and flags
+are S Z AC P CY.
+
+  s [n]              step n instructions (default 1)
+  c [n]              continue until a breakpoint, halt or error, giving up
+                     after n instructions (default 1000000)
+  rs [n]             step back n instructions (needs history)
+  rc                 continue backwards to the previous breakpoint
+  hist [on|n|off]    record the last n (default 100000) instructions for stepping
+                     back, or show status
+  b [addr] [cond]    set a breakpoint, stopping only when cond holds, or list them
+  i <addr> <n>       ignore the next n hits of a breakpoint
+  d <addr>           delete a breakpoint
+  wr <addr> [end]    stop after reads from addr-end
+  ww <addr> [end]    stop after writes to addr-end
+  wa <addr> [end]    stop after reads or writes to addr-end
+  wl                 list watchpoints
+  wd <addr>          delete watchpoints starting at addr
+  who <addr>         show which instruction last wrote addr (needs prov)
+  prov [on|off]      track the last writer of every address, or show status
+  bt                 show the calls leading to PC (needs cs)
+  cs [on|off]        keep a shadow call stack, stopping on mismatched returns,
+                     or show status
+  smc [on|off]       stop when code is overwritten or written data executed,
+                     or show status
+  r                  show registers and flags
+  r <reg> <value>    set A B C D E H L BC DE HL SP or PC
+  f <flag> <0|1>     set flag S Z AC P or CY
+  m <addr> [len]     hexdump memory
+  w <addr> <byte>..  write bytes to memory
+  l [addr] [n]       disassemble n instructions (default around PC)
+  save <file>        write a save state
+  load <file>        restore a save state, clearing history, the call stack
+                     and what coverage, --provenance, --detect-smc and
+                     --detect-uninit have tracked
+  snap               remember the current state for diff
+  diff [file]        show what changed since snap, or since a save state
+  h                  show this help
+  q                  quit
+";
+
+// Instructions shown before PC when listing around it
+const LIST_BEFORE: usize = 3;
+const LIST_LENGTH: usize = 10;
+// Instructions c runs before handing back control, as there's no other way
+// to interrupt a program that never stops, such as the invaders game loop
+const CONTINUE_LIMIT: u64 = 0x1000000;
+
+pub fn run<R: BufRead, W: Write>(debugger: &mut Debugger, input: R, output: &mut W) -> io::Result<()> {
+    show_state(&debugger.system.cpu, output)?;
+
+    let mut last = String::new();
+    let mut lines = input.lines();
+    loop {
+        write!(output, "> ")?;
+        output.flush()?;
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => return Ok(()),
+        };
+        let line = if line.trim().is_empty() { last.clone() } else { line };
+        last = line.clone();
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((command, args)) = words.split_first() else {
+            continue;
+        };
+        if *command == "q" {
+            return Ok(());
+        }
+        if let Err(message) = execute(debugger, command, args, output)? {
+            writeln!(output, "{}", message)?;
+        }
+    }
+}
+
+// Outer error is a failed write, inner one a bad command
+fn execute<W: Write>(
+    debugger: &mut Debugger,
+    command: &str,
+    args: &[&str],
+    output: &mut W,
+) -> io::Result<Result<(), String>> {
+    let cpu = &mut debugger.system.cpu;
+    match (command, args) {
+        ("h" | "?", _) => write!(output, "{}", HELP)?,
+        ("s", _) => {
+            let count = match args.first() {
+                Some(n) => match parse_hex(n) {
+                    Some(n) => n as u64,
+                    None => return Ok(Err(format!("Invalid count '{}'", n))),
+                },
+                None => 1,
+            };
+            let stop = debugger.step(count);
+            report(debugger, stop, output)?;
+        }
+        ("c", _) if args.len() <= 1 => {
+            let limit = match args.first() {
+                Some(n) => match parse_hex(n) {
+                    Some(n) => n as u64,
+                    None => return Ok(Err(format!("Invalid count '{}'", n))),
+                },
+                None => CONTINUE_LIMIT,
+            };
+            let stop = debugger.step(limit);
+            if matches!(stop, Stop::Done) {
+                writeln!(output, "Still running after {:x} instructions", limit)?;
+            }
+            report(debugger, stop, output)?;
+        }
+        ("rs", _) if args.len() <= 1 => {
+            let count = match args.first() {
+                Some(n) => match parse_hex(n) {
+                    Some(n) => n as u64,
+                    None => return Ok(Err(format!("Invalid count '{}'", n))),
+                },
+                None => 1,
+            };
+            if debugger.history.is_none() {
+                return Ok(Err("History is off, turn it on with hist".to_string()));
+            }
+            let stop = debugger.step_back(count);
+            report(debugger, stop, output)?;
+        }
+        ("rc", []) => {
+            if debugger.history.is_none() {
+                return Ok(Err("History is off, turn it on with hist".to_string()));
+            }
+            let stop = debugger.reverse_cont();
+            report(debugger, stop, output)?;
+        }
+        ("hist", []) => match &debugger.history {
+            Some(history) => writeln!(
+                output,
+                "Recording, {} of {} instructions kept",
+                history.len(),
+                history.capacity()
+            )?,
+            None => writeln!(output, "History is off")?,
+        },
+        ("hist", ["off"]) => debugger.history = None,
+        ("hist", ["on"]) => debugger.history = Some(History::new(DEFAULT_CAPACITY)),
+        ("hist", [size]) => match parse_hex(size) {
+            Some(size) => debugger.history = Some(History::new(size)),
+            None => return Ok(Err(format!("Invalid size '{}'", size))),
+        },
+        ("b", []) => {
+            for (addr, breakpoint) in &debugger.breakpoints {
+                writeln!(output, "0x{:04x}  {}", addr, breakpoint)?;
+            }
+        }
+        ("b", [addr, condition @ ..]) => {
+            let Some(addr) = parse_address(addr) else {
+                return Ok(Err(format!("Invalid address '{}'", addr)));
+            };
+            let condition = if condition.is_empty() {
+                None
+            } else {
+                match Condition::parse(&condition.join(" ")) {
+                    Ok(condition) => Some(condition),
+                    Err(message) => return Ok(Err(message)),
+                }
+            };
+            debugger.breakpoints.insert(addr, Breakpoint::new(condition));
+        }
+        ("i", [addr, count]) => {
+            let breakpoint = parse_address(addr).and_then(|addr| debugger.breakpoints.get_mut(&addr));
+            let Some(breakpoint) = breakpoint else {
+                return Ok(Err(format!("No breakpoint at '{}'", addr)));
+            };
+            match parse_hex(count) {
+                Some(count) => breakpoint.ignore = count as u64,
+                None => return Ok(Err(format!("Invalid count '{}'", count))),
+            }
+        }
+        ("d", [addr]) => match parse_address(addr) {
+            Some(addr) if debugger.breakpoints.remove(&addr).is_some() => {}
+            _ => return Ok(Err(format!("No breakpoint at '{}'", addr))),
+        },
+        ("wr" | "ww" | "wa", [start, rest @ ..]) if rest.len() <= 1 => {
+            let Some(start) = parse_address(start) else {
+                return Ok(Err(format!("Invalid address '{}'", start)));
+            };
+            let end = match rest.first() {
+                Some(end) => match parse_address(end).filter(|end| *end >= start) {
+                    Some(end) => end,
+                    None => return Ok(Err(format!("Invalid end address '{}'", end))),
+                },
+                None => start,
+            };
+            let kind = match command {
+                "wr" => WatchKind::Read,
+                "ww" => WatchKind::Write,
+                _ => WatchKind::Access,
+            };
+            cpu.watchpoints.push(Watchpoint { start, end, kind });
+        }
+        ("wl", []) => {
+            for watchpoint in &cpu.watchpoints {
+                writeln!(output, "{}", watchpoint)?;
+            }
+        }
+        ("wd", [addr]) => {
+            let before = cpu.watchpoints.len();
+            if let Some(addr) = parse_address(addr) {
+                cpu.watchpoints.retain(|w| w.start != addr);
+            }
+            if cpu.watchpoints.len() == before {
+                return Ok(Err(format!("No watchpoint at '{}'", addr)));
+            }
+        }
+        ("who", [addr]) => {
+            let Some(addr) = parse_address(addr) else {
+                return Ok(Err(format!("Invalid address '{}'", addr)));
+            };
+            let Some(provenance) = &cpu.provenance else {
+                return Ok(Err("Write tracking is off, turn it on with prov".to_string()));
+            };
+            match provenance.last_write(addr) {
+                Some(record) => writeln!(output, "0x{:04x} was last written {}", addr, record)?,
+                None => writeln!(output, "0x{:04x} has not been written since tracking started", addr)?,
+            }
+        }
+        ("prov", []) => {
+            let state = if cpu.provenance.is_some() { "on" } else { "off" };
+            writeln!(output, "Write tracking is {}", state)?;
+        }
+        ("prov", ["on"]) => {
+            if cpu.provenance.is_none() {
+                cpu.provenance = Some(Box::default());
+            }
+        }
+        ("prov", ["off"]) => cpu.provenance = None,
+        ("bt", []) => {
+            let Some(backtrace) = debugger.backtrace() else {
+                return Ok(Err("Call stack tracking is off, turn it on with cs".to_string()));
+            };
+            for line in backtrace {
+                writeln!(output, "{}", line)?;
+            }
+        }
+        ("cs", []) => match &cpu.call_stack {
+            Some(call_stack) => writeln!(output, "Tracking calls, {} frames deep", call_stack.frames.len())?,
+            None => writeln!(output, "Call stack tracking is off")?,
+        },
+        ("cs", ["on"]) => {
+            if cpu.call_stack.is_none() {
+                cpu.call_stack = Some(CallStack::default());
+            }
+        }
+        ("cs", ["off"]) => cpu.call_stack = None,
+        ("smc", []) => {
+            let state = if cpu.self_mod.is_some() { "on" } else { "off" };
+            writeln!(output, "Self-modifying code detection is {}", state)?;
+        }
+        ("smc", ["on"]) => {
+            if cpu.self_mod.is_none() {
+                cpu.self_mod = Some(Box::default());
+            }
+        }
+        ("smc", ["off"]) => cpu.self_mod = None,
+        ("r", []) => show_state(cpu, output)?,
+        ("r", [register, value]) => {
+            let Some(value) = parse_hex(value) else {
+                return Ok(Err(format!("Invalid value '{}'", value)));
+            };
+            if let Err(message) = set_register(cpu, register, value) {
+                return Ok(Err(message));
+            }
+        }
+        ("f", [flag, value]) => {
+            let set = match *value {
+                "0" => false,
+                "1" => true,
+                _ => return Ok(Err(format!("Invalid flag value '{}'", value))),
+            };
+            let flags = &mut cpu.flags;
+            match flag.to_ascii_uppercase().as_str() {
+                "S" => flags.S = set,
+                "Z" => flags.Z = set,
+                "AC" => flags.AC = set,
+                "P" => flags.P = set,
+                "CY" => flags.C = set,
+                _ => return Ok(Err(format!("Unknown flag '{}'", flag))),
+            }
+        }
+        ("m", [addr, rest @ ..]) if rest.len() <= 1 => {
+            let Some(start) = parse_address(addr) else {
+                return Ok(Err(format!("Invalid address '{}'", addr)));
+            };
+            let len = match rest.first() {
+                Some(len) => match parse_hex(len) {
+                    Some(len) => len,
+                    None => return Ok(Err(format!("Invalid length '{}'", len))),
+                },
+                None => 0x40,
+            };
+            let Some(end) = (start as usize).checked_add(len) else {
+                return Ok(Err(format!("Length '{}' is too large", rest[0])));
+            };
+            let end = end.min(MEMORY_SIZE);
+            for line in hexdump(&cpu.memory[start as usize..end], start) {
+                writeln!(output, "{}", line)?;
+            }
+        }
+        ("w", [addr, bytes @ ..]) if !bytes.is_empty() => {
+            let Some(start) = parse_address(addr) else {
+                return Ok(Err(format!("Invalid address '{}'", addr)));
+            };
+            for (i, byte) in bytes.iter().enumerate() {
+                match parse_hex(byte).filter(|b| *b <= 0xff) {
+                    Some(b) => cpu.memory[(start as usize + i) % MEMORY_SIZE] = b as u8,
+                    None => return Ok(Err(format!("Invalid byte '{}'", byte))),
+                }
+            }
+        }
+        ("l", _) if args.len() <= 2 => {
+            let start = match args.first() {
+                Some(addr) => match parse_address(addr) {
+                    Some(addr) => addr,
+                    None => return Ok(Err(format!("Invalid address '{}'", addr))),
+                },
+                None => find_start_before(&cpu.memory, cpu.registers.PC, LIST_BEFORE),
+            };
+            let count = match args.get(1) {
+                Some(n) => match parse_hex(n) {
+                    Some(n) => n,
+                    None => return Ok(Err(format!("Invalid count '{}'", n))),
+                },
+                None => LIST_LENGTH,
+            };
+            // Each instruction is at least a byte, so this stops at the end
+            // of memory
+            let count = count.min(MEMORY_SIZE - start as usize);
+            list(cpu, start, count, output)?;
+        }
+        ("save", [file]) => {
+            if let Err(e) = debugger.system.save_state().write(Path::new(file)) {
+                return Ok(Err(e.to_string()));
+            }
+        }
+        ("load", [file]) => {
+            let result = Snapshot::read(Path::new(file)).and_then(|state| debugger.system.load_state(&state));
+            if let Err(e) = result {
+                return Ok(Err(e.to_string()));
+            }
+            // Recorded steps belong to the state being replaced
+            if let Some(history) = &mut debugger.history {
+                *history = History::new(history.capacity());
+            }
+            show_state(&debugger.system.cpu, output)?;
+        }
+        ("snap", []) => debugger.snapshot = Some(debugger.system.save_state()),
+        ("diff", []) => {
+            let Some(snapshot) = &debugger.snapshot else {
+                return Ok(Err("No snapshot, take one with snap".to_string()));
+            };
+            write!(output, "{}", diff(snapshot, &debugger.system).report(DEFAULT_PREVIEW))?;
+        }
+        ("diff", [file]) => {
+            let snapshot = match Snapshot::read(Path::new(file)) {
+                Ok(snapshot) => snapshot,
+                Err(e) => return Ok(Err(e.to_string())),
+            };
+            write!(output, "{}", diff(&snapshot, &debugger.system).report(DEFAULT_PREVIEW))?;
+        }
+        _ => return Ok(Err(format!("Unknown command '{}', h for help", command))),
+    }
+    Ok(Ok(()))
+}
+
+fn report<W: Write>(debugger: &Debugger, stop: Stop, output: &mut W) -> io::Result<()> {
+    if !matches!(stop, Stop::Done) {
+        writeln!(output, "{}", stop)?;
+    }
+    show_state(&debugger.system.cpu, output)
+}
+
+pub fn show_state<W: Write>(cpu: &I8080, output: &mut W) -> io::Result<()> {
+    let r = &cpu.registers;
+    let f = &cpu.flags;
+    let flag = |set: bool, name: &'static str| if set { name } else { "-" };
+    writeln!(
+        output,
+        "A={:02x} BC={:02x}{:02x} DE={:02x}{:02x} HL={:02x}{:02x} SP={:04x} PC={:04x} F={}{}{}{}{} cycles={}",
+        r.A,
+        r.B,
+        r.C,
+        r.D,
+        r.E,
+        r.H,
+        r.L,
+        r.SP,
+        r.PC,
+        flag(f.S, "S"),
+        flag(f.Z, "Z"),
+        flag(f.AC, "AC"),
+        flag(f.P, "P"),
+        flag(f.C, "CY"),
+        cpu.cycles
+    )?;
+    list(cpu, r.PC, 1, output)
+}
+
+fn list<W: Write>(cpu: &I8080, start: u16, count: usize, output: &mut W) -> io::Result<()> {
+    for line in listing(&cpu.memory, start, count, cpu.registers.PC) {
+        writeln!(output, "{}", line)?;
+    }
+    Ok(())
+}
+
+fn set_register(cpu: &mut I8080, register: &str, value: usize) -> Result<(), String> {
+    let r = &mut cpu.registers;
+    let byte = value as u8;
+    let [high, low] = (value as u16).to_be_bytes();
+    let fits = match register.to_ascii_uppercase().as_str() {
+        "A" | "B" | "C" | "D" | "E" | "H" | "L" => value <= 0xff,
+        _ => value <= 0xffff,
+    };
+    if !fits {
+        return Err(format!("0x{:x} is too large for {}", value, register));
+    }
+    match register.to_ascii_uppercase().as_str() {
+        "A" => r.A = byte,
+        "B" => r.B = byte,
+        "C" => r.C = byte,
+        "D" => r.D = byte,
+        "E" => r.E = byte,
+        "H" => r.H = byte,
+        "L" => r.L = byte,
+        "BC" => (r.B, r.C) = (high, low),
+        "DE" => (r.D, r.E) = (high, low),
+        "HL" => (r.H, r.L) = (high, low),
+        "SP" => r.SP = value as u16,
+        "PC" => r.PC = value as u16,
+        _ => return Err(format!("Unknown register '{}'", register)),
+    }
+    Ok(())
+}
+
+fn parse_hex(text: &str) -> Option<usize> {
+    let digits = text.strip_prefix("0x").unwrap_or(text);
+    usize::from_str_radix(digits, 16).ok()
+}
+
+fn parse_address(text: &str) -> Option<u16> {
+    parse_hex(text).filter(|n| *n < MEMORY_SIZE).map(|n| n as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::{MachineType, System};
+
+    // Runs a script against program loaded at 0, returning the output
+    fn session(machine: MachineType, program: &[u8], script: &str) -> String {
+        let mut system = System::new(machine);
+        system.cpu.memory[..program.len()].copy_from_slice(program);
+        let mut debugger = Debugger::new(system);
+        let mut output = Vec::new();
+        run(&mut debugger, script.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    // LXI SP,0100; MVI A,42; CALL 0010; HLT, then at 0010 INR A; RET
+    fn program() -> Vec<u8> {
+        let mut program = vec![0; 0x12];
+        program[..9].copy_from_slice(&[0x31, 0x00, 0x01, 0x3e, 0x42, 0xcd, 0x10, 0x00, 0x76]);
+        program[0x10..].copy_from_slice(&[0x3c, 0xc9]);
+        program
+    }
+
+    #[test]
+    fn steps_and_stops_at_breakpoints() {
+        let output = session(MachineType::Bare, &program(), "b 10\nb\nc\ns\n\nc\n");
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "A=00 BC=0000 DE=0000 HL=0000 SP=0000 PC=0000 F=----- cycles=0",
+                "=> 0000  31 00 01  LXI SP,0100",
+                "> > 0x0010  hits 0",
+                "> Breakpoint at 0x0010",
+                "A=42 BC=0000 DE=0000 HL=0000 SP=00fe PC=0010 F=----- cycles=34",
+                "=> 0010  3c        INR A",
+                "> A=43 BC=0000 DE=0000 HL=0000 SP=00fe PC=0011 F=----- cycles=39",
+                "=> 0011  c9        RET",
+                "> A=43 BC=0000 DE=0000 HL=0000 SP=0100 PC=0008 F=----- cycles=49",
+                "=> 0008  76        HLT",
+                "> Halted",
+                "A=43 BC=0000 DE=0000 HL=0000 SP=0100 PC=0009 F=----- cycles=56",
+                "=> 0009  00        NOOP",
+                "> ",
+            ]
+        );
+    }
+
+    #[test]
+    fn shows_and_edits_registers_and_memory() {
+        let script = "r hl 1234\nr a 100\nf cy 1\nf c 1\nr\nw 20 de ad\nm 1e 4\nm fffe 10\n";
+        let output = session(MachineType::Bare, &program(), script);
+        let lines: Vec<&str> = output.lines().skip(2).collect();
+        assert_eq!(
+            lines,
+            [
+                "> > 0x100 is too large for a",
+                "> > Unknown flag 'c'",
+                "> A=00 BC=0000 DE=0000 HL=1234 SP=0000 PC=0000 F=----CY cycles=0",
+                "=> 0000  31 00 01  LXI SP,0100",
+                "> > 001e  00 00 de ad                                      ....",
+                "> fffe  00 00                                            ..",
+                "> ",
+            ]
+        );
+    }
+
+    #[test]
+    fn lists_no_further_than_the_end_of_memory() {
+        let output = session(MachineType::Bare, &program(), "l 5 2\nl fff0 ffffffff\n");
+        let lines: Vec<&str> = output.lines().skip(2).collect();
+        assert_eq!(lines[..2], [">    0005  cd 10 00  CALL 0010", "   0008  76        HLT"]);
+        assert_eq!(lines.len(), 2 + 16 + 1);
+        assert_eq!(lines[17], "   ffff  00        NOOP");
+    }
+
+    #[test]
+    fn gives_up_continuing_a_program_that_never_stops() {
+        // JMP 0000, which the invaders machine never treats as finished
+        let output = session(MachineType::Invaders, &[0xc3, 0x00, 0x00], "c 10\n");
+        assert!(output.contains("> Still running after 10 instructions\n"), "{}", output);
+        assert!(output.contains("cycles=160\n"), "{}", output);
+    }
+}
@@ -0,0 +1,179 @@
+// Post-mortem reports for when a program dies. The last few instructions
+// executed are kept in a ring buffer so the report can show how the CPU got
+// where it is, followed by the code around PC, the stack and the memory
+// HL and SP point at.
+
+use std::collections::VecDeque;
+
+use crate::cpu::{Registers, StatusFlags, I8080};
+use crate::disassembler::{disassemble_instr, find_start_before, listing};
+use crate::symbols::Symbols;
+use crate::utils::{hexdump, merge_bytes};
+
+// Instructions kept when no length is given
+pub const DEFAULT_LENGTH: usize = 32;
+
+// Instructions listed either side of PC
+const CODE_BEFORE: usize = 5;
+const CODE_AFTER: usize = 5;
+// Return address sized words shown from SP up
+const STACK_WORDS: u16 = 8;
+// Bytes dumped before and after HL and SP
+const NEAR_BEFORE: u16 = 0x10;
+const NEAR_AFTER: u16 = 0x20;
+
+// Longest instruction, in bytes
+const MAX_INSTRUCTION: usize = 3;
+
+// CPU state from just before an instruction executed. Only the raw bytes
+// are kept, as recording happens on every instruction and disassembling
+// them is left to the report.
+struct Executed {
+    bytes: [u8; MAX_INSTRUCTION],
+    registers: Registers,
+    flags: StatusFlags,
+    cycles: u64,
+}
+
+pub struct RecentInstructions {
+    entries: VecDeque<Executed>,
+    capacity: usize,
+}
+
+impl RecentInstructions {
+    pub fn new(capacity: usize) -> RecentInstructions {
+        RecentInstructions {
+            entries: VecDeque::new(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    // Call before executing each instruction
+    pub fn record(&mut self, cpu: &I8080) {
+        let pc = cpu.registers.PC as usize;
+        let bytes = std::array::from_fn(|i| cpu.memory[(pc + i) % cpu.memory.len()]);
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(Executed {
+            bytes,
+            registers: cpu.registers.clone(),
+            flags: cpu.flags.clone(),
+            cycles: cpu.cycles,
+        });
+    }
+}
+
+// Builds the report for a CPU stopped by cause. The last instruction listed
+// is the one that failed.
+pub fn report(cpu: &I8080, recent: &RecentInstructions, symbols: &Symbols, cause: &str) -> String {
+    let r = &cpu.registers;
+    let mut lines = vec![
+        "=== Crash report ===".to_string(),
+        format!("Cause: {}", cause),
+        format!("State: {}  cycles={}", state(r, &cpu.flags), cpu.cycles),
+        format!("PC:    {}", symbols.describe(r.PC)),
+    ];
+
+    lines.push(format!("--- Last {} instructions ---", recent.entries.len()));
+    for entry in &recent.entries {
+        let (text, seek) = disassemble_instr(&entry.bytes, 0);
+        let bytes: Vec<String> = entry.bytes[..=seek as usize]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        lines.push(format!(
+            "   {:04x}  {:<9} {:<16} {}  cycles={}",
+            entry.registers.PC,
+            bytes.join(" "),
+            text,
+            state(&entry.registers, &entry.flags),
+            entry.cycles
+        ));
+    }
+
+    lines.push("--- Code around PC ---".to_string());
+    let start = find_start_before(&cpu.memory, r.PC, CODE_BEFORE);
+    let count = CODE_BEFORE + 1 + CODE_AFTER;
+    lines.extend(listing(&cpu.memory, start, count, r.PC));
+
+    lines.push(format!("--- Stack from SP 0x{:04x} ---", r.SP));
+    for i in 0..STACK_WORDS {
+        let addr = r.SP.wrapping_add(i * 2);
+        let word = merge_bytes(cpu.memory[addr.wrapping_add(1) as usize], cpu.memory[addr as usize]);
+        lines.push(format!("   {:04x}  {}", addr, symbols.describe(word)));
+    }
+
+    let hl = merge_bytes(r.H, r.L);
+    for (name, addr) in [("HL", hl), ("SP", r.SP)] {
+        lines.push(format!("--- Memory near {} 0x{:04x} ---", name, addr));
+        lines.extend(near(&cpu.memory, addr));
+    }
+
+    if let Some(call_stack) = &cpu.call_stack {
+        lines.push("--- Backtrace ---".to_string());
+        for line in call_stack.backtrace(r.PC, symbols) {
+            lines.push(format!("   {}", line));
+        }
+    }
+
+    let mut text = lines.join("\n");
+    text.push('\n');
+    text
+}
+
+fn state(r: &Registers, f: &StatusFlags) -> String {
+    let flag = |set: bool, name: char| if set { name } else { '-' };
+    format!(
+        "A={:02x} BC={:02x}{:02x} DE={:02x}{:02x} HL={:02x}{:02x} SP={:04x} {}{}{}{}{}",
+        r.A,
+        r.B,
+        r.C,
+        r.D,
+        r.E,
+        r.H,
+        r.L,
+        r.SP,
+        flag(f.S, 'S'),
+        flag(f.Z, 'Z'),
+        flag(f.AC, 'A'),
+        flag(f.P, 'P'),
+        flag(f.C, 'C'),
+    )
+}
+
+// Hexdump of the memory around an address, clipped to the address space
+fn near(memory: &[u8], addr: u16) -> Vec<String> {
+    let start = addr.saturating_sub(NEAR_BEFORE) & !0xf;
+    let end = (addr as usize + NEAR_AFTER as usize).min(memory.len());
+    hexdump(&memory[start as usize..end], start)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_recent_instructions_with_their_bytes() {
+        let mut cpu = I8080::new();
+        // LXI SP,0100; MVI A,42; HLT
+        cpu.memory[..6].copy_from_slice(&[0x31, 0x00, 0x01, 0x3e, 0x42, 0x76]);
+        let mut recent = RecentInstructions::new(2);
+        for _ in 0..3 {
+            recent.record(&cpu);
+            cpu.step().unwrap();
+        }
+
+        let text = report(&cpu, &recent, &Symbols::default(), "test");
+        let listed: Vec<&str> = text
+            .lines()
+            .skip_while(|line| !line.starts_with("--- Last"))
+            .take(3)
+            .collect();
+        assert_eq!(listed[0], "--- Last 2 instructions ---");
+        assert!(listed[1].starts_with("   0003  3e 42     MVI A,42 "), "{}", listed[1]);
+        assert!(listed[1].ends_with("cycles=10"), "{}", listed[1]);
+        assert!(listed[2].starts_with("   0005  76        HLT "), "{}", listed[2]);
+    }
+}
@@ -0,0 +1,250 @@
+// Execution profile: how often each address was executed and the cycles
+// spent there, with the calls made between them. Counts are grouped into
+// subroutines, each running from its entry point up to the next one. Entry
+// points are the labels of a symbol table when one is given, otherwise the
+// targets of CALLs, RSTs and interrupts plus the address profiling started
+// at.
+//
+// Cycles are counted against the instruction that spent them, so a
+// subroutine's cycles don't include those of the subroutines it calls.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::cpu::MEMORY_SIZE;
+use crate::symbols::Symbols;
+
+// Addresses listed in the hotspot report
+const HOTSPOTS: usize = 20;
+
+pub struct Profiler {
+    executions: Vec<u64>,
+    cycles: Vec<u64>,
+    entries: BTreeSet<u16>,
+    // Times each call site called each target
+    calls: BTreeMap<(u16, u16), u64>,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler {
+            executions: vec![0; MEMORY_SIZE],
+            cycles: vec![0; MEMORY_SIZE],
+            entries: BTreeSet::new(),
+            calls: BTreeMap::new(),
+        }
+    }
+}
+
+// Totals for one subroutine
+#[derive(Debug, Clone)]
+pub struct Routine {
+    // None for code before the first entry point
+    pub entry: Option<u16>,
+    pub name: String,
+    // Times it was called, RSTs and interrupts included
+    pub calls: u64,
+    pub instructions: u64,
+    pub cycles: u64,
+}
+
+impl Profiler {
+    // Call after executing the instruction at pc, which took cycles
+    pub fn record(&mut self, pc: u16, cycles: u64) {
+        if self.entries.is_empty() {
+            self.entries.insert(pc);
+        }
+        self.executions[pc as usize] += 1;
+        self.cycles[pc as usize] += cycles;
+    }
+
+    pub fn record_call(&mut self, call_site: u16, target: u16) {
+        self.entries.insert(target);
+        *self.calls.entry((call_site, target)).or_default() += 1;
+    }
+
+    pub fn executions(&self, address: u16) -> u64 {
+        self.executions[address as usize]
+    }
+
+    pub fn cycles(&self, address: u16) -> u64 {
+        self.cycles[address as usize]
+    }
+
+    // Entry point of the subroutine holding an address, None for code
+    // before the first one
+    fn routine_of(&self, address: u16, symbols: &Symbols) -> Option<u16> {
+        if symbols.is_empty() {
+            self.entries.range(..=address).next_back().copied()
+        } else {
+            symbols.lookup(address).map(|(_, offset)| address - offset)
+        }
+    }
+
+    fn routine_name(entry: Option<u16>, symbols: &Symbols) -> String {
+        match entry {
+            Some(entry) => match symbols.get(entry) {
+                Some(name) => name.to_string(),
+                None => format!("sub_{:04x}", entry),
+            },
+            None => "(no routine)".to_string(),
+        }
+    }
+
+    // Every subroutine that executed or was called, most cycles first
+    pub fn routines(&self, symbols: &Symbols) -> Vec<Routine> {
+        let mut totals: BTreeMap<Option<u16>, Routine> = BTreeMap::new();
+        let new_routine = |entry: Option<u16>| Routine {
+            entry,
+            name: Profiler::routine_name(entry, symbols),
+            calls: 0,
+            instructions: 0,
+            cycles: 0,
+        };
+        for address in 0..MEMORY_SIZE {
+            if self.executions[address] > 0 {
+                let entry = self.routine_of(address as u16, symbols);
+                let routine = totals.entry(entry).or_insert_with(|| new_routine(entry));
+                routine.instructions += self.executions[address];
+                routine.cycles += self.cycles[address];
+            }
+        }
+        for (&(_, target), &count) in &self.calls {
+            let entry = self.routine_of(target, symbols);
+            totals.entry(entry).or_insert_with(|| new_routine(entry)).calls += count;
+        }
+        let mut routines: Vec<Routine> = totals.into_values().collect();
+        routines.sort_by(|a, b| b.cycles.cmp(&a.cycles).then(a.entry.cmp(&b.entry)));
+        routines
+    }
+
+    // Text report of the busiest addresses and subroutines
+    pub fn report(&self, symbols: &Symbols) -> String {
+        let total_instructions: u64 = self.executions.iter().sum();
+        let total_cycles: u64 = self.cycles.iter().sum();
+        let percent = |cycles: u64| cycles as f64 * 100.0 / total_cycles.max(1) as f64;
+
+        let mut lines = vec![
+            format!("Profile of {} instructions, {} cycles", total_instructions, total_cycles),
+            String::new(),
+            format!("Hotspots, top {} addresses by cycles", HOTSPOTS),
+            "  address  executions      cycles       %  location".to_string(),
+        ];
+        let mut addresses: Vec<usize> = (0..MEMORY_SIZE).filter(|a| self.executions[*a] > 0).collect();
+        addresses.sort_by(|a, b| self.cycles[*b].cmp(&self.cycles[*a]).then(a.cmp(b)));
+        for address in addresses.into_iter().take(HOTSPOTS) {
+            lines.push(format!(
+                "  0x{:04x}  {:>10}  {:>10}  {:>5.1}%  {}",
+                address,
+                self.executions[address],
+                self.cycles[address],
+                percent(self.cycles[address]),
+                symbols.describe(address as u16)
+            ));
+        }
+
+        lines.push(String::new());
+        lines.push("Subroutines by cycles, not counting the subroutines they call".to_string());
+        lines.push("  entry        calls  instructions      cycles       %  name".to_string());
+        for routine in self.routines(symbols) {
+            let entry = match routine.entry {
+                Some(entry) => format!("0x{:04x}", entry),
+                None => "-".to_string(),
+            };
+            lines.push(format!(
+                "  {:<6}  {:>10}  {:>12}  {:>10}  {:>5.1}%  {}",
+                entry,
+                routine.calls,
+                routine.instructions,
+                routine.cycles,
+                percent(routine.cycles),
+                routine.name
+            ));
+        }
+
+        let mut text = lines.join("\n");
+        text.push('\n');
+        text
+    }
+
+    // Call graph in Graphviz DOT format, nodes labelled with their cycles
+    // and edges with how many calls were made
+    pub fn call_graph(&self, symbols: &Symbols) -> String {
+        let mut edges: BTreeMap<(Option<u16>, Option<u16>), u64> = BTreeMap::new();
+        for (&(site, target), &count) in &self.calls {
+            let key = (self.routine_of(site, symbols), self.routine_of(target, symbols));
+            *edges.entry(key).or_default() += count;
+        }
+
+        let mut lines = vec!["digraph calls {".to_string(), "  node [shape=box];".to_string()];
+        for routine in self.routines(symbols) {
+            lines.push(format!(
+                "  \"{}\" [label=\"{}\\n{} cycles\"];",
+                routine.name, routine.name, routine.cycles
+            ));
+        }
+        for ((from, to), count) in edges {
+            lines.push(format!(
+                "  \"{}\" -> \"{}\" [label=\"{}\"];",
+                Profiler::routine_name(from, symbols),
+                Profiler::routine_name(to, symbols),
+                count
+            ));
+        }
+        lines.push("}".to_string());
+
+        let mut text = lines.join("\n");
+        text.push('\n');
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::I8080;
+
+    // LXI SP,0100; CALL 0010; CALL 0010; HLT, then at 0010 INR A; RET
+    fn profile() -> Profiler {
+        let mut cpu = I8080::new();
+        cpu.profiler = Some(Box::default());
+        cpu.memory[..10].copy_from_slice(&[0x31, 0x00, 0x01, 0xcd, 0x10, 0x00, 0xcd, 0x10, 0x00, 0x76]);
+        cpu.memory[0x10..0x12].copy_from_slice(&[0x3c, 0xc9]);
+        while !cpu.halted {
+            cpu.step().unwrap();
+        }
+        *cpu.profiler.unwrap()
+    }
+
+    fn totals(routines: &[Routine]) -> Vec<(&str, u64, u64, u64)> {
+        routines
+            .iter()
+            .map(|r| (r.name.as_str(), r.calls, r.instructions, r.cycles))
+            .collect()
+    }
+
+    #[test]
+    fn groups_addresses_into_subroutines_at_call_targets() {
+        let profiler = profile();
+        assert_eq!(profiler.executions(0x0010), 2);
+        assert_eq!(profiler.cycles(0x0011), 20);
+        // The caller's 51 cycles are its own 10 + 17 + 17 + 7, and leave out
+        // the 2 * (5 + 10) spent in the subroutine
+        let routines = profiler.routines(&Symbols::default());
+        assert_eq!(totals(&routines), [("sub_0000", 0, 4, 51), ("sub_0010", 2, 4, 30)]);
+
+        let report = profiler.report(&Symbols::default());
+        assert!(report.starts_with("Profile of 8 instructions, 81 cycles\n"));
+        let graph = profiler.call_graph(&Symbols::default());
+        assert!(graph.contains("  \"sub_0000\" -> \"sub_0010\" [label=\"2\"];\n"));
+    }
+
+    #[test]
+    fn groups_by_symbols_when_given() {
+        let symbols = Symbols::parse("0000 start\n0006 second\n0010 bump\n").unwrap();
+        let routines = profile().routines(&symbols);
+        assert_eq!(
+            totals(&routines),
+            [("bump", 2, 4, 30), ("start", 0, 2, 27), ("second", 0, 2, 24)]
+        );
+    }
+}
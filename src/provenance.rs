@@ -0,0 +1,82 @@
+// Tracks which instruction last wrote each byte of memory, to answer "who
+// wrote this and when" while chasing a corrupted value.
+//
+// Only writes made by executed instructions are tracked, bytes placed by
+// the loader or edited from a debugger show as never written. Stepping
+// backwards does not rewind it.
+
+use std::fmt;
+
+use crate::cpu::MEMORY_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WriteRecord {
+    // Address of the instruction that did the write
+    pub pc: u16,
+    // CPU cycle count when the write happened
+    pub cycle: u64,
+}
+
+impl fmt::Display for WriteRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "by the instruction at 0x{:04x}, cycle {}", self.pc, self.cycle)
+    }
+}
+
+pub struct Provenance {
+    writers: Vec<Option<WriteRecord>>,
+}
+
+impl Default for Provenance {
+    fn default() -> Self {
+        Provenance {
+            writers: vec![None; MEMORY_SIZE],
+        }
+    }
+}
+
+impl Provenance {
+    pub fn record(&mut self, address: u16, pc: u16, cycle: u64) {
+        self.writers[address as usize] = Some(WriteRecord { pc, cycle });
+    }
+
+    // Last write to an address, None if it has not been written
+    pub fn last_write(&self, address: u16) -> Option<WriteRecord> {
+        self.writers[address as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::{MachineType, System};
+
+    #[test]
+    fn records_the_writing_instruction_across_a_load_state() {
+        let mut system = System::new(MachineType::Bare);
+        system.cpu.provenance = Some(Box::default());
+        // LXI H,2000; MVI M,55; STA 2001; HLT
+        let program = [0x21, 0x00, 0x20, 0x36, 0x55, 0x32, 0x01, 0x20, 0x76];
+        system.cpu.memory[..program.len()].copy_from_slice(&program);
+        system.step().unwrap();
+        let state = system.save_state();
+        system.step().unwrap();
+        system.step().unwrap();
+
+        let provenance = system.cpu.provenance.as_ref().unwrap();
+        let record = provenance.last_write(0x2000).unwrap();
+        assert_eq!(record, WriteRecord { pc: 0x0003, cycle: 20 });
+        assert_eq!(record.to_string(), "by the instruction at 0x0003, cycle 20");
+        assert_eq!(provenance.last_write(0x2001).unwrap().pc, 0x0005);
+        assert_eq!(provenance.last_write(0x0000), None);
+
+        // Writes from before the state was loaded are forgotten, and writes
+        // after it are recorded as before
+        system.load_state(&state).unwrap();
+        assert_eq!(system.cpu.provenance.as_ref().unwrap().last_write(0x2001), None);
+        system.step().unwrap();
+        let provenance = system.cpu.provenance.as_ref().unwrap();
+        assert_eq!(provenance.last_write(0x2000).unwrap().pc, 0x0003);
+        assert_eq!(provenance.last_write(0x2001), None);
+    }
+}
@@ -0,0 +1,121 @@
+// Read-only memory. Writes to bytes marked as ROM never change them. Real
+// hardware just drops such writes, which is what a machine emulation
+// wants, but while debugging a stray store into ROM is usually a bug worth
+// hearing about, so they can also be reported with the instruction and
+// value. Each instruction and address pair is reported once.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::cpu::MEMORY_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RomWrites {
+    // Drop them silently, as the hardware would
+    Ignore,
+    // Drop them and report them
+    Report,
+}
+
+impl RomWrites {
+    pub fn from_name(name: &str) -> Option<RomWrites> {
+        match name {
+            "ignore" => Some(RomWrites::Ignore),
+            "report" => Some(RomWrites::Report),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RomWrite {
+    pub pc: u16,
+    pub address: u16,
+    pub value: u8,
+}
+
+impl fmt::Display for RomWrite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Instruction at 0x{:04x} wrote 0x{:02x} to ROM at 0x{:04x}",
+            self.pc, self.value, self.address
+        )
+    }
+}
+
+pub struct RomMap {
+    read_only: Vec<bool>,
+    pub policy: RomWrites,
+    reported: HashSet<(u16, u16)>,
+    // Writes since the debugger last cleared them
+    pub writes: Vec<RomWrite>,
+}
+
+impl RomMap {
+    pub fn new(policy: RomWrites) -> RomMap {
+        RomMap {
+            read_only: vec![false; MEMORY_SIZE],
+            policy,
+            reported: HashSet::new(),
+            writes: Vec::new(),
+        }
+    }
+
+    // Marks start-end inclusive as ROM
+    pub fn protect(&mut self, start: u16, end: u16) {
+        self.read_only[start as usize..=end as usize].fill(true);
+    }
+
+    // Returns whether the write may go ahead
+    pub fn write(&mut self, pc: u16, address: u16, value: u8) -> bool {
+        if !self.read_only[address as usize] {
+            return true;
+        }
+        if self.policy == RomWrites::Report && self.reported.insert((pc, address)) {
+            self.writes.push(RomWrite { pc, address, value });
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::I8080;
+    use crate::watchpoints::{Access, WatchKind, Watchpoint};
+
+    #[test]
+    fn refuses_writes_and_reports_each_once() {
+        let mut rom = RomMap::new(RomWrites::Report);
+        rom.protect(0x0000, 0x00ff);
+        assert!(rom.write(0x10, 0x0100, 1));
+        assert!(!rom.write(0x10, 0x00ff, 2));
+        assert!(!rom.write(0x10, 0x00ff, 3));
+        assert_eq!(rom.writes, [RomWrite { pc: 0x10, address: 0x00ff, value: 2 }]);
+
+        let mut rom = RomMap::new(RomWrites::Ignore);
+        rom.protect(0x0000, 0x00ff);
+        assert!(!rom.write(0x10, 0x0000, 1));
+        assert!(rom.writes.is_empty());
+    }
+
+    #[test]
+    fn watchpoints_see_refused_writes() {
+        let mut cpu = I8080::new();
+        let mut rom = RomMap::new(RomWrites::Report);
+        rom.protect(0x1000, 0x1fff);
+        cpu.rom = Some(Box::new(rom));
+        cpu.watchpoints.push(Watchpoint { start: 0x1000, end: 0x1000, kind: WatchKind::Write });
+        // MVI A,55; STA 1000
+        cpu.memory[..5].copy_from_slice(&[0x3e, 0x55, 0x32, 0x00, 0x10]);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+
+        assert_eq!(cpu.memory[0x1000], 0);
+        assert_eq!(cpu.watch_hits.len(), 1);
+        let hit = &cpu.watch_hits[0];
+        assert_eq!((hit.pc, hit.address, hit.access, hit.new), (2, 0x1000, Access::Write, 0x55));
+        assert_eq!(cpu.rom.as_ref().unwrap().writes.len(), 1);
+    }
+}
@@ -0,0 +1,142 @@
+use std::fmt;
+
+use crate::checksum::{crc32, sha1, to_hex};
+use crate::loader::RomSet;
+
+// Expected checksums of a single chip in a known-good dump
+pub struct KnownRom {
+    pub name: &'static str,
+    pub crc32: u32,
+    pub sha1: &'static str,
+}
+
+pub struct Machine {
+    pub name: &'static str,
+    pub roms: &'static [KnownRom],
+}
+
+pub const INVADERS: Machine = Machine {
+    name: "invaders",
+    roms: &[
+        KnownRom { name: "invaders.h", crc32: 0x734f5ad8, sha1: "ff6200af4c9110d8181249cbcef1a8a40fa40b7f" },
+        KnownRom { name: "invaders.g", crc32: 0x6bfaca4a, sha1: "16f48649b531bdef8c2d1446c429b5f414524350" },
+        KnownRom { name: "invaders.f", crc32: 0x0ccead96, sha1: "537aef03468f63c5b9e11dd61e253f7ae17d9743" },
+        KnownRom { name: "invaders.e", crc32: 0x14e538b0, sha1: "1d6ca0c99f9df71e2990b610deb9d7da0125e2d8" },
+    ],
+};
+
+pub const MACHINES: &[Machine] = &[INVADERS];
+
+pub fn find_machine(name: &str) -> Option<&'static Machine> {
+    MACHINES.iter().find(|machine| machine.name == name)
+}
+
+#[derive(Debug)]
+pub enum Mismatch {
+    // A chip the machine needs is not in the ROM set
+    Missing { name: String },
+    // A loaded file the machine does not know about
+    Unknown { name: String },
+    BadDump { name: String, crc32: u32, expected_crc32: u32, sha1: String, expected_sha1: String },
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mismatch::Missing { name } => write!(f, "{}: missing from ROM set", name),
+            Mismatch::Unknown { name } => write!(f, "{}: not part of this machine", name),
+            Mismatch::BadDump { name, crc32, expected_crc32, sha1, expected_sha1 } => write!(
+                f,
+                "{}: bad dump (crc32 {:08x}, expected {:08x}; sha1 {}, expected {})",
+                name, crc32, expected_crc32, sha1, expected_sha1
+            ),
+        }
+    }
+}
+
+// Chips are matched by file name, ignoring any leading directories
+pub fn verify(rom_set: &RomSet, machine: &Machine) -> Vec<Mismatch> {
+    let mut mismatches = Vec::new();
+
+    for known in machine.roms {
+        if !rom_set.segments.iter().any(|s| file_name(&s.name) == known.name) {
+            mismatches.push(Mismatch::Missing { name: known.name.to_string() });
+        }
+    }
+
+    for segment in &rom_set.segments {
+        let name = file_name(&segment.name);
+        let known = match machine.roms.iter().find(|known| known.name == name) {
+            Some(known) => known,
+            None => {
+                mismatches.push(Mismatch::Unknown { name: name.to_string() });
+                continue;
+            }
+        };
+
+        let crc = crc32(&segment.data);
+        let digest = to_hex(&sha1(&segment.data));
+        if crc != known.crc32 || digest != known.sha1 {
+            mismatches.push(Mismatch::BadDump {
+                name: name.to_string(),
+                crc32: crc,
+                expected_crc32: known.crc32,
+                sha1: digest,
+                expected_sha1: known.sha1.to_string(),
+            });
+        }
+    }
+
+    mismatches
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit(['/', '\\']).next().unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loader::Segment;
+
+    const TEST: Machine = Machine {
+        name: "test",
+        roms: &[
+            // crc32 and sha1 of "abc"
+            KnownRom { name: "good.bin", crc32: 0x352441c2, sha1: "a9993e364706816aba3e25717850c26c9cd0d89d" },
+            KnownRom { name: "absent.bin", crc32: 0, sha1: "" },
+        ],
+    };
+
+    fn segment(name: &str, address: u16, data: &[u8]) -> Segment {
+        Segment { name: name.to_string(), address, data: data.to_vec(), read_only: false }
+    }
+
+    #[test]
+    fn verify_reports_each_kind_of_mismatch() {
+        let rom_set = RomSet {
+            segments: vec![segment("roms/good.bin", 0, b"abc"), segment("extra.bin", 0x100, b"x")],
+            entry: None,
+        };
+        let mismatches = verify(&rom_set, &TEST);
+        assert_eq!(mismatches.len(), 2);
+        assert!(matches!(&mismatches[0], Mismatch::Missing { name } if name == "absent.bin"));
+        assert!(matches!(&mismatches[1], Mismatch::Unknown { name } if name == "extra.bin"));
+    }
+
+    #[test]
+    fn verify_reports_bad_dumps() {
+        let rom_set = RomSet { segments: vec![segment("good.bin", 0, b"abd")], entry: None };
+        let mismatches = verify(&rom_set, &TEST);
+        assert!(matches!(
+            &mismatches[1],
+            Mismatch::BadDump { name, expected_crc32: 0x352441c2, .. } if name == "good.bin"
+        ));
+    }
+
+    #[test]
+    fn invaders_is_known() {
+        assert_eq!(find_machine("invaders").map(|machine| machine.roms.len()), Some(4));
+        assert!(find_machine("pacman").is_none());
+    }
+}
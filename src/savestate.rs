@@ -0,0 +1,328 @@
+// Save states: everything needed to carry on running a System from where it
+// was, in a versioned binary file. Multi-byte values are little-endian.
+//
+//   "I8080SAV"                    magic
+//   u16                           format version, currently 1
+//   u8 + bytes                    machine name
+//   A B C D E H L                 registers
+//   u16 u16                       SP, PC
+//   u8                            flags, packed as PUSH PSW would
+//   u8 u8                         halted, interrupts enabled
+//   u64                           cycles
+//   u64 u8                        cycle the next machine interrupt is due
+//                                 at and its vector
+//   u16 + bytes                   attached device state
+//   65536 bytes                   memory
+//   u32                           CRC-32 of everything before it
+//
+// Debugging state such as breakpoints, history and the analysis hooks isn't
+// saved, only what the program itself could observe.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::checksum::crc32;
+use crate::cpu::{Registers, StatusFlags, MEMORY_SIZE};
+use crate::machine::MachineType;
+
+const MAGIC: &[u8; 8] = b"I8080SAV";
+const VERSION: u16 = 1;
+
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub machine: MachineType,
+    pub registers: Registers,
+    pub flags: StatusFlags,
+    pub halted: bool,
+    pub interrupts_enabled: bool,
+    pub cycles: u64,
+    pub next_interrupt: u64,
+    pub next_vector: u8,
+    pub device: Vec<u8>,
+    pub memory: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum StateError {
+    Io { path: PathBuf, message: String },
+    NotSaveState,
+    UnsupportedVersion(u16),
+    Truncated,
+    Corrupt(String),
+    WrongMachine { expected: MachineType, found: MachineType },
+    BadDevice,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::Io { path, message } => write!(f, "{}: {}", path.display(), message),
+            StateError::NotSaveState => write!(f, "Not a save state"),
+            StateError::UnsupportedVersion(version) => write!(
+                f,
+                "Save state is version {}, only version {} is supported",
+                version, VERSION
+            ),
+            StateError::Truncated => write!(f, "Save state is truncated"),
+            StateError::Corrupt(message) => write!(f, "Save state is corrupt: {}", message),
+            StateError::WrongMachine { expected, found } => write!(
+                f,
+                "Save state is for the {} machine, not {}",
+                found.name(),
+                expected.name()
+            ),
+            StateError::BadDevice => write!(f, "Save state's device state doesn't fit the machine"),
+        }
+    }
+}
+
+impl Snapshot {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&VERSION.to_le_bytes());
+        let name = self.machine.name().as_bytes();
+        data.push(name.len() as u8);
+        data.extend_from_slice(name);
+        let r = &self.registers;
+        data.extend_from_slice(&[r.A, r.B, r.C, r.D, r.E, r.H, r.L]);
+        data.extend_from_slice(&r.SP.to_le_bytes());
+        data.extend_from_slice(&r.PC.to_le_bytes());
+        data.push(self.flags.to_byte());
+        data.push(self.halted as u8);
+        data.push(self.interrupts_enabled as u8);
+        data.extend_from_slice(&self.cycles.to_le_bytes());
+        data.extend_from_slice(&self.next_interrupt.to_le_bytes());
+        data.push(self.next_vector);
+        data.extend_from_slice(&(self.device.len() as u16).to_le_bytes());
+        data.extend_from_slice(&self.device);
+        data.extend_from_slice(&self.memory);
+        let crc = crc32(&data);
+        data.extend_from_slice(&crc.to_le_bytes());
+        data
+    }
+
+    // Checks everything before handing anything back, so a bad file never
+    // leaves a machine half restored
+    pub fn decode(data: &[u8]) -> Result<Snapshot, StateError> {
+        let mut reader = Reader { data, pos: 0 };
+        if reader.bytes(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+            return Err(StateError::NotSaveState);
+        }
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        let name_len = reader.u8()? as usize;
+        let name = String::from_utf8_lossy(reader.bytes(name_len)?).into_owned();
+        let machine = MachineType::from_name(&name)
+            .ok_or_else(|| StateError::Corrupt(format!("unknown machine '{}'", name)))?;
+        let [a, b, c, d, e, h, l] = reader.bytes(7)?.try_into().unwrap_or_default();
+        let sp = reader.u16()?;
+        let pc = reader.u16()?;
+        let flags = StatusFlags::from_byte(reader.u8()?);
+        let halted = reader.bool()?;
+        let interrupts_enabled = reader.bool()?;
+        let cycles = reader.u64()?;
+        let next_interrupt = reader.u64()?;
+        let next_vector = reader.u8()?;
+        let device_len = reader.u16()? as usize;
+        let device = reader.bytes(device_len)?.to_vec();
+        let memory = reader.bytes(MEMORY_SIZE)?.to_vec();
+
+        let end = reader.pos;
+        let crc = u32::from_le_bytes(reader.bytes(4)?.try_into().unwrap_or_default());
+        if reader.pos != data.len() {
+            return Err(StateError::Corrupt("unexpected data at the end".to_string()));
+        }
+        if crc != crc32(&data[..end]) {
+            return Err(StateError::Corrupt("checksum mismatch".to_string()));
+        }
+
+        Ok(Snapshot {
+            machine,
+            registers: Registers { A: a, B: b, C: c, D: d, E: e, H: h, L: l, PC: pc, SP: sp },
+            flags,
+            halted,
+            interrupts_enabled,
+            cycles,
+            next_interrupt,
+            next_vector,
+            device,
+            memory,
+        })
+    }
+
+    pub fn read(path: &Path) -> Result<Snapshot, StateError> {
+        let data = fs::read(path).map_err(|e| StateError::Io {
+            path: path.to_path_buf(),
+            message: e.to_string(),
+        })?;
+        Snapshot::decode(&data)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), StateError> {
+        fs::write(path, self.encode()).map_err(|e| StateError::Io {
+            path: path.to_path_buf(),
+            message: e.to_string(),
+        })
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        let bytes = self.data.get(self.pos..self.pos + len).ok_or(StateError::Truncated)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            other => Err(StateError::Corrupt(format!("invalid flag byte 0x{:02x}", other))),
+        }
+    }
+
+    fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap_or_default()))
+    }
+
+    fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap_or_default()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::System;
+    use crate::statistics::Statistics;
+
+    fn invaders_state() -> Snapshot {
+        let mut system = System::new(MachineType::Invaders);
+        let cpu = &mut system.cpu;
+        cpu.registers.A = 0x12;
+        cpu.registers.SP = 0x2400;
+        cpu.registers.PC = 0x0abc;
+        cpu.flags.C = true;
+        cpu.interrupts_enabled = true;
+        cpu.cycles = 123_456;
+        cpu.memory[0x2000] = 0x55;
+        cpu.memory[0xffff] = 0xaa;
+        system.save_state()
+    }
+
+    #[test]
+    fn round_trips() {
+        let state = invaders_state();
+        let data = state.encode();
+        assert_eq!(&data[..8], b"I8080SAV");
+        let decoded = Snapshot::decode(&data).unwrap();
+        assert_eq!(decoded.encode(), data);
+
+        let mut system = System::new(MachineType::Invaders);
+        system.load_state(&decoded).unwrap();
+        let cpu = &system.cpu;
+        assert_eq!((cpu.registers.A, cpu.registers.SP, cpu.registers.PC), (0x12, 0x2400, 0x0abc));
+        assert!(cpu.flags.C && !cpu.flags.Z);
+        assert!(cpu.interrupts_enabled && !cpu.halted);
+        assert_eq!(cpu.cycles, 123_456);
+        assert_eq!((cpu.memory[0x2000], cpu.memory[0xffff]), (0x55, 0xaa));
+        assert_eq!(system.save_state().encode(), data);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let mut data = invaders_state().encode();
+        data[0] = b'X';
+        assert!(matches!(Snapshot::decode(&data), Err(StateError::NotSaveState)));
+        assert!(matches!(Snapshot::decode(b"I8080"), Err(StateError::NotSaveState)));
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut data = invaders_state().encode();
+        data[8..10].copy_from_slice(&2u16.to_le_bytes());
+        assert!(matches!(Snapshot::decode(&data), Err(StateError::UnsupportedVersion(2))));
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let data = invaders_state().encode();
+        for len in [10, 40, data.len() - 5, data.len() - 1] {
+            assert!(
+                matches!(Snapshot::decode(&data[..len]), Err(StateError::Truncated)),
+                "{} bytes",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_corrupt_files() {
+        let mut data = invaders_state().encode();
+        data[100] ^= 0xff;
+        let error = Snapshot::decode(&data).unwrap_err();
+        assert!(matches!(error, StateError::Corrupt(_)));
+        assert_eq!(error.to_string(), "Save state is corrupt: checksum mismatch");
+
+        let mut data = invaders_state().encode();
+        data.push(0);
+        let error = Snapshot::decode(&data).unwrap_err();
+        assert_eq!(error.to_string(), "Save state is corrupt: unexpected data at the end");
+    }
+
+    #[test]
+    fn refuses_another_machines_state() {
+        let mut system = System::new(MachineType::Cpm);
+        let error = system.load_state(&invaders_state()).unwrap_err();
+        assert!(matches!(
+            error,
+            StateError::WrongMachine { expected: MachineType::Cpm, found: MachineType::Invaders }
+        ));
+    }
+
+    #[test]
+    fn loading_starts_analysis_over() {
+        let mut system = System::new(MachineType::Invaders);
+        let cpu = &mut system.cpu;
+        cpu.uninit = Some(Box::default());
+        cpu.coverage = Some(Box::default());
+        cpu.self_mod = Some(Box::default());
+        cpu.provenance = Some(Box::default());
+        cpu.call_stack = Some(Default::default());
+        cpu.profiler = Some(Box::default());
+        cpu.statistics = Some(Box::default());
+        cpu.heatmap = Some(Box::default());
+        // LXI H,2000; MOV A,M; HLT
+        cpu.memory[..5].copy_from_slice(&[0x21, 0x00, 0x20, 0x7e, 0x76]);
+        cpu.memory[0x2000] = 0x55;
+        cpu.step().unwrap();
+        let mut state = system.save_state();
+        state.registers.PC = 0x0003;
+        system.cpu.provenance.as_mut().unwrap().record(0x2000, 0x10, 5);
+
+        system.load_state(&state).unwrap();
+        let cpu = &mut system.cpu;
+        assert_eq!(cpu.coverage.as_ref().unwrap().flags(0x0000), 0);
+        assert!(cpu.provenance.as_ref().unwrap().last_write(0x2000).is_none());
+        assert_eq!(cpu.profiler.as_ref().unwrap().executions(0x0000), 0);
+        let fresh = Statistics::default().report();
+        assert_eq!(cpu.statistics.as_ref().unwrap().report(), fresh);
+        assert!(cpu.heatmap.as_ref().unwrap().pixels().iter().all(|&byte| byte == 0));
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.A, 0x55);
+        assert!(cpu.uninit.as_ref().unwrap().reads.is_empty());
+    }
+}
@@ -0,0 +1,164 @@
+// Self-modifying code detection. Reports writes to bytes that have already
+// been executed, and execution of bytes the program wrote as data. Bytes
+// placed by the loader count as neither, only what the program does itself.
+//
+// Each combination of instruction and address is reported once, so a loop
+// patching the same byte doesn't bury everything else.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::cpu::MEMORY_SIZE;
+
+const EXECUTED: u8 = 0b01;
+const WRITTEN: u8 = 0b10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CodeEventKind {
+    // A byte that has been executed was overwritten
+    WriteToCode,
+    // A byte written by the program was executed
+    ExecuteData,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CodeEvent {
+    pub kind: CodeEventKind,
+    // Instruction doing the write, or being executed
+    pub pc: u16,
+    pub address: u16,
+    // For ExecuteData, the instruction that last wrote the byte
+    pub writer: Option<u16>,
+}
+
+impl fmt::Display for CodeEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            CodeEventKind::WriteToCode => write!(
+                f,
+                "Instruction at 0x{:04x} wrote to 0x{:04x}, which has been executed",
+                self.pc, self.address
+            ),
+            CodeEventKind::ExecuteData => {
+                write!(
+                    f,
+                    "Instruction at 0x{:04x} executed 0x{:04x}, which was written as data",
+                    self.pc, self.address
+                )?;
+                if let Some(writer) = self.writer {
+                    write!(f, " by 0x{:04x}", writer)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+pub struct SelfModDetector {
+    flags: Vec<u8>,
+    // Last instruction to write each address
+    writers: Vec<u16>,
+    reported: HashSet<(CodeEventKind, u16, u16)>,
+    // Events since the debugger last cleared them
+    pub events: Vec<CodeEvent>,
+}
+
+impl Default for SelfModDetector {
+    fn default() -> Self {
+        SelfModDetector {
+            flags: vec![0; MEMORY_SIZE],
+            writers: vec![0; MEMORY_SIZE],
+            reported: HashSet::new(),
+            events: Vec::new(),
+        }
+    }
+}
+
+impl SelfModDetector {
+    // Call for each opcode and operand byte fetched by the instruction at pc
+    pub fn fetch(&mut self, pc: u16, address: u16) {
+        let flags = &mut self.flags[address as usize];
+        *flags |= EXECUTED;
+        if *flags & WRITTEN != 0 {
+            let writer = Some(self.writers[address as usize]);
+            self.report(CodeEventKind::ExecuteData, pc, address, writer);
+        }
+    }
+
+    pub fn write(&mut self, pc: u16, address: u16) {
+        let flags = &mut self.flags[address as usize];
+        *flags |= WRITTEN;
+        self.writers[address as usize] = pc;
+        if *flags & EXECUTED != 0 {
+            self.report(CodeEventKind::WriteToCode, pc, address, None);
+        }
+    }
+
+    fn report(&mut self, kind: CodeEventKind, pc: u16, address: u16, writer: Option<u16>) {
+        if self.reported.insert((kind, pc, address)) {
+            self.events.push(CodeEvent { kind, pc, address, writer });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::I8080;
+
+    fn run(program: &[u8]) -> Vec<CodeEvent> {
+        let mut cpu = I8080::new();
+        cpu.self_mod = Some(Box::default());
+        cpu.memory[..program.len()].copy_from_slice(program);
+        while !cpu.halted {
+            cpu.step().unwrap();
+        }
+        cpu.self_mod.unwrap().events
+    }
+
+    #[test]
+    fn reports_writes_to_executed_code() {
+        // MVI A,00; STA 0001; HLT, overwriting the MVI's operand
+        let events = run(&[0x3e, 0x00, 0x32, 0x01, 0x00, 0x76]);
+        let expected = CodeEvent {
+            kind: CodeEventKind::WriteToCode,
+            pc: 0x0002,
+            address: 0x0001,
+            writer: None,
+        };
+        assert_eq!(events, [expected]);
+        assert_eq!(
+            events[0].to_string(),
+            "Instruction at 0x0002 wrote to 0x0001, which has been executed"
+        );
+    }
+
+    #[test]
+    fn reports_executing_written_bytes() {
+        // MVI A,76; STA 0006; NOP; then the HLT just written
+        let events = run(&[0x3e, 0x76, 0x32, 0x06, 0x00, 0x00, 0x00]);
+        let expected = CodeEvent {
+            kind: CodeEventKind::ExecuteData,
+            pc: 0x0006,
+            address: 0x0006,
+            writer: Some(0x0002),
+        };
+        assert_eq!(events, [expected]);
+        assert_eq!(
+            events[0].to_string(),
+            "Instruction at 0x0006 executed 0x0006, which was written as data by 0x0002"
+        );
+    }
+
+    #[test]
+    fn reports_each_instruction_and_address_once() {
+        let mut detector = SelfModDetector::default();
+        detector.fetch(0x0000, 0x0010);
+        for _ in 0..3 {
+            detector.write(0x0020, 0x0010);
+        }
+        detector.write(0x0030, 0x0010);
+        let writers: Vec<u16> = detector.events.iter().map(|event| event.pc).collect();
+        assert_eq!(writers, [0x0020, 0x0030]);
+    }
+}
@@ -0,0 +1,341 @@
+// Differences between two machine states, such as snapshots taken before and
+// after a routine runs. Registers, flags, the rest of the CPU state and the
+// machine's pending interrupt and device state are listed old -> new, and
+// differing memory is grouped into ranges with both sides shown as hex and
+// ASCII.
+//
+// Changed bytes separated by only a few unchanged ones go in the same range,
+// so a routine filling every other byte of a buffer shows as one range
+// rather than hundreds.
+
+use crate::cpu::{Registers, StatusFlags};
+use crate::machine::System;
+use crate::savestate::Snapshot;
+use crate::utils::hexdump;
+
+// Unchanged bytes allowed between changed ones in a range
+const MERGE_GAP: usize = 4;
+// Bytes of each range shown by default
+pub const DEFAULT_PREVIEW: usize = 64;
+
+// The parts of a state that are compared, from a save state or a live
+// system
+pub struct StateView<'a> {
+    pub registers: &'a Registers,
+    pub flags: &'a StatusFlags,
+    pub halted: bool,
+    pub interrupts_enabled: bool,
+    pub cycles: u64,
+    pub next_interrupt: u64,
+    pub next_vector: u8,
+    // As saved by the attached device, opaque bytes
+    pub device: Vec<u8>,
+    pub memory: &'a [u8],
+}
+
+impl<'a> From<&'a Snapshot> for StateView<'a> {
+    fn from(snapshot: &'a Snapshot) -> Self {
+        StateView {
+            registers: &snapshot.registers,
+            flags: &snapshot.flags,
+            halted: snapshot.halted,
+            interrupts_enabled: snapshot.interrupts_enabled,
+            cycles: snapshot.cycles,
+            next_interrupt: snapshot.next_interrupt,
+            next_vector: snapshot.next_vector,
+            device: snapshot.device.clone(),
+            memory: &snapshot.memory,
+        }
+    }
+}
+
+impl<'a> From<&'a System> for StateView<'a> {
+    fn from(system: &'a System) -> Self {
+        let cpu = &system.cpu;
+        StateView {
+            registers: &cpu.registers,
+            flags: &cpu.flags,
+            halted: cpu.halted,
+            interrupts_enabled: cpu.interrupts_enabled,
+            cycles: cpu.cycles,
+            next_interrupt: system.next_interrupt,
+            next_vector: system.next_vector,
+            device: cpu.io.save(),
+            memory: &cpu.memory,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub name: &'static str,
+    pub old: String,
+    pub new: String,
+}
+
+// Inclusive range of memory holding at least one changed byte
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryRange {
+    pub start: u16,
+    pub end: u16,
+    pub changed: usize,
+    pub old: Vec<u8>,
+    pub new: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+pub struct StateDiff {
+    pub registers: Vec<Change>,
+    pub flags: Vec<Change>,
+    // Cycles, halted and interrupt enable
+    pub cpu: Vec<Change>,
+    // Next interrupt and its vector, and device state
+    pub machine: Vec<Change>,
+    pub memory: Vec<MemoryRange>,
+}
+
+pub fn diff<'a, 'b>(old: impl Into<StateView<'a>>, new: impl Into<StateView<'b>>) -> StateDiff {
+    let (old, new) = (old.into(), new.into());
+    let mut result = StateDiff::default();
+
+    let (a, b) = (old.registers, new.registers);
+    let bytes = [
+        ("A", a.A, b.A),
+        ("B", a.B, b.B),
+        ("C", a.C, b.C),
+        ("D", a.D, b.D),
+        ("E", a.E, b.E),
+        ("H", a.H, b.H),
+        ("L", a.L, b.L),
+    ];
+    for (name, old, new) in bytes {
+        change(&mut result.registers, name, old, new, |v| format!("{:02x}", v));
+    }
+    change(&mut result.registers, "SP", a.SP, b.SP, |v| format!("{:04x}", v));
+    change(&mut result.registers, "PC", a.PC, b.PC, |v| format!("{:04x}", v));
+
+    let (a, b) = (old.flags, new.flags);
+    let flags = [
+        ("S", a.S, b.S),
+        ("Z", a.Z, b.Z),
+        ("AC", a.AC, b.AC),
+        ("P", a.P, b.P),
+        ("CY", a.C, b.C),
+    ];
+    for (name, old, new) in flags {
+        change(&mut result.flags, name, old, new, |v| (v as u8).to_string());
+    }
+
+    if old.cycles != new.cycles {
+        let delta = new.cycles as i128 - old.cycles as i128;
+        result.cpu.push(Change {
+            name: "cycles",
+            old: old.cycles.to_string(),
+            new: format!("{} ({:+})", new.cycles, delta),
+        });
+    }
+    change(&mut result.cpu, "halted", old.halted, new.halted, yes_no);
+    change(&mut result.cpu, "interrupts", old.interrupts_enabled, new.interrupts_enabled, |v| {
+        if v { "enabled" } else { "disabled" }.to_string()
+    });
+
+    change(&mut result.machine, "interrupt", old.next_interrupt, new.next_interrupt, |v| {
+        format!("cycle {}", v)
+    });
+    change(&mut result.machine, "vector", old.next_vector, new.next_vector, |v| format!("{:02x}", v));
+    if old.device != new.device {
+        result.machine.push(Change { name: "device", old: hex(&old.device), new: hex(&new.device) });
+    }
+
+    result.memory = memory_ranges(old.memory, new.memory);
+    result
+}
+
+fn change<T: PartialEq + Copy>(
+    changes: &mut Vec<Change>,
+    name: &'static str,
+    old: T,
+    new: T,
+    format: impl Fn(T) -> String,
+) {
+    if old != new {
+        changes.push(Change { name, old: format(old), new: format(new) });
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    if bytes.is_empty() {
+        return "none".to_string();
+    }
+    bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ")
+}
+
+fn yes_no(value: bool) -> String {
+    if value { "yes" } else { "no" }.to_string()
+}
+
+fn plural(count: usize, noun: &str) -> String {
+    match count {
+        1 => format!("1 {}", noun),
+        _ => format!("{} {}s", count, noun),
+    }
+}
+
+fn memory_ranges(old: &[u8], new: &[u8]) -> Vec<MemoryRange> {
+    let mut ranges: Vec<MemoryRange> = Vec::new();
+    let differing = old.iter().zip(new).enumerate().filter(|(_, (a, b))| a != b);
+    for (address, _) in differing {
+        match ranges.last_mut() {
+            Some(range) if address - range.end as usize <= MERGE_GAP + 1 => {
+                range.end = address as u16;
+                range.changed += 1;
+            }
+            _ => ranges.push(MemoryRange {
+                start: address as u16,
+                end: address as u16,
+                changed: 1,
+                old: Vec::new(),
+                new: Vec::new(),
+            }),
+        }
+    }
+    for range in &mut ranges {
+        let span = range.start as usize..=range.end as usize;
+        range.old = old[span.clone()].to_vec();
+        range.new = new[span].to_vec();
+    }
+    ranges
+}
+
+impl StateDiff {
+    pub fn is_empty(&self) -> bool {
+        self.registers.is_empty()
+            && self.flags.is_empty()
+            && self.cpu.is_empty()
+            && self.machine.is_empty()
+            && self.memory.is_empty()
+    }
+
+    // Showing up to preview bytes of each memory range, old lines marked -
+    // and new ones +
+    pub fn report(&self, preview: usize) -> String {
+        if self.is_empty() {
+            return "No differences\n".to_string();
+        }
+        let mut lines = Vec::new();
+        let groups = [
+            ("Registers", &self.registers),
+            ("Flags", &self.flags),
+            ("CPU", &self.cpu),
+            ("Machine", &self.machine),
+        ];
+        for (title, changes) in groups {
+            if changes.is_empty() {
+                continue;
+            }
+            lines.push(format!("{}:", title));
+            for change in changes {
+                lines.push(format!("  {:<10} {} -> {}", change.name, change.old, change.new));
+            }
+        }
+
+        if !self.memory.is_empty() {
+            let changed: usize = self.memory.iter().map(|range| range.changed).sum();
+            lines.push(format!(
+                "Memory: {} changed in {}",
+                plural(changed, "byte"),
+                plural(self.memory.len(), "range")
+            ));
+            for range in &self.memory {
+                let len = range.old.len();
+                lines.push(format!(
+                    "  {:04x}-{:04x}, {} of {} bytes changed",
+                    range.start, range.end, range.changed, len
+                ));
+                let shown = len.min(preview);
+                let old = hexdump(&range.old[..shown], range.start);
+                let new = hexdump(&range.new[..shown], range.start);
+                for (old, new) in old.iter().zip(&new) {
+                    lines.push(format!("  - {}", old));
+                    lines.push(format!("  + {}", new));
+                }
+                if shown < len {
+                    lines.push(format!("  ... {} more bytes", len - shown));
+                }
+            }
+        }
+
+        let mut report = lines.join("\n");
+        report.push('\n');
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::MachineType;
+
+    #[test]
+    fn reports_nothing_for_the_same_state() {
+        let system = System::new(MachineType::Invaders);
+        let snapshot = system.save_state();
+        let difference = diff(&snapshot, &system);
+        assert!(difference.is_empty());
+        assert_eq!(difference.report(DEFAULT_PREVIEW), "No differences\n");
+    }
+
+    #[test]
+    fn lists_cpu_and_machine_changes() {
+        let mut system = System::new(MachineType::Invaders);
+        let old = system.save_state();
+        system.cpu.registers.A = 0x42;
+        system.cpu.registers.SP = 0x2400;
+        system.cpu.flags.Z = true;
+        system.cpu.cycles = 100;
+        system.cpu.interrupts_enabled = true;
+        system.next_interrupt += 16_666;
+        system.next_vector = 2;
+        // Shift register data port
+        system.cpu.io.output(4, 0xab);
+
+        let difference = diff(&old, &system);
+        let names = |changes: &[Change]| changes.iter().map(|c| c.name).collect::<Vec<_>>();
+        assert_eq!(names(&difference.registers), ["A", "SP"]);
+        assert_eq!(names(&difference.flags), ["Z"]);
+        assert_eq!(names(&difference.cpu), ["cycles", "interrupts"]);
+        assert_eq!(names(&difference.machine), ["interrupt", "vector", "device"]);
+        assert_eq!(difference.cpu[0].new, "100 (+100)");
+        let vector = Change { name: "vector", old: "01".to_string(), new: "02".to_string() };
+        assert_eq!(difference.machine[1], vector);
+        assert_eq!(difference.machine[2].old, "0e 08 00 00 00 00");
+        assert_eq!(difference.machine[2].new, "0e 08 00 00 ab 00");
+        assert!(difference.memory.is_empty());
+
+        let report = difference.report(DEFAULT_PREVIEW);
+        assert!(report.contains("Machine:\n  interrupt  cycle 16666 -> cycle 33332\n"), "{}", report);
+    }
+
+    #[test]
+    fn groups_nearby_memory_changes() {
+        let mut system = System::new(MachineType::Bare);
+        let old = system.save_state();
+        let memory = &mut system.cpu.memory;
+        // Every other byte, then one well away from them
+        for address in (0x2000..0x2010).step_by(2) {
+            memory[address] = 0xff;
+        }
+        memory[0x3000] = 1;
+
+        let difference = diff(&old, &system);
+        let ranges: Vec<(u16, u16, usize)> = difference
+            .memory
+            .iter()
+            .map(|range| (range.start, range.end, range.changed))
+            .collect();
+        assert_eq!(ranges, [(0x2000, 0x200e, 8), (0x3000, 0x3000, 1)]);
+        let report = difference.report(4);
+        assert!(report.contains("Memory: 9 bytes changed in 2 ranges\n"), "{}", report);
+        assert!(report.contains("  ... 11 more bytes\n"), "{}", report);
+    }
+}
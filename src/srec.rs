@@ -0,0 +1,181 @@
+// Motorola S-record images (S19, S28 and S37)
+//
+// Each line is a record of the form
+//   S<type><count><address><data><checksum>
+// where the count covers the address, data and checksum bytes and the
+// checksum is the ones' complement of the sum of everything after the type.
+// S1/S2/S3 records carry data with 16, 24 and 32 bit addresses and
+// S9/S8/S7 hold the matching start address.
+
+use crate::cpu::MEMORY_SIZE;
+use crate::loader::{LoadError, RomSet, Segment};
+use crate::utils::decode_hex;
+
+pub fn parse(name: &str, text: &str) -> Result<RomSet, LoadError> {
+    let mut rom_set = RomSet::default();
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut data_records = 0;
+
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let parse_error = |message: String| LoadError::Parse { line: line_number, message };
+
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let record_type = match line.strip_prefix('S').and_then(|rest| rest.chars().next()) {
+            Some(t) => t,
+            None => return Err(parse_error("Record does not start with 'S'".to_string())),
+        };
+        let bytes = line
+            .get(2..)
+            .and_then(decode_hex)
+            .ok_or_else(|| parse_error("Invalid hex digits".to_string()))?;
+        if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+            return Err(parse_error("Record length does not match byte count".to_string()));
+        }
+        let sum = bytes[..bytes.len() - 1].iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+        let expected = !sum;
+        if bytes[bytes.len() - 1] != expected {
+            return Err(parse_error(format!(
+                "Bad checksum 0x{:02x}, expected 0x{:02x}",
+                bytes[bytes.len() - 1],
+                expected
+            )));
+        }
+
+        let address_len = match record_type {
+            '0' | '1' | '5' | '9' => 2,
+            '2' | '6' | '8' => 3,
+            '3' | '7' => 4,
+            _ => return Err(parse_error(format!("Unknown record type S{}", record_type))),
+        };
+        if bytes.len() < address_len + 2 {
+            return Err(parse_error("Record too short for its address".to_string()));
+        }
+        let address = bytes[1..=address_len]
+            .iter()
+            .fold(0usize, |acc, b| (acc << 8) | *b as usize);
+        let data = &bytes[address_len + 1..bytes.len() - 1];
+
+        match record_type {
+            // Header, usually the module name
+            '0' => {}
+            '1' | '2' | '3' => {
+                if address + data.len() > MEMORY_SIZE {
+                    return Err(parse_error(format!(
+                        "{} bytes at 0x{:x} do not fit in memory",
+                        data.len(),
+                        address
+                    )));
+                }
+                data_records += 1;
+                match segments.last_mut() {
+                    Some(last) if last.address as usize + last.data.len() == address => {
+                        last.data.extend_from_slice(data);
+                    }
+                    _ => segments.push(Segment {
+                        name: name.to_string(),
+                        address: address as u16,
+                        data: data.to_vec(),
+                        read_only: false,
+                    }),
+                }
+            }
+            // Record count, checks nothing was lost in transit
+            '5' | '6' => {
+                if address != data_records {
+                    return Err(parse_error(format!(
+                        "Record count is {}, but {} data records were read",
+                        address, data_records
+                    )));
+                }
+            }
+            _ => {
+                if address >= MEMORY_SIZE {
+                    return Err(parse_error(format!("Start address 0x{:x} beyond 64K", address)));
+                }
+                rom_set.entry = Some(address as u16);
+            }
+        }
+    }
+
+    for segment in segments {
+        rom_set.push(segment)?;
+    }
+    Ok(rom_set)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Builds a record from its type, address bytes and data
+    fn record(record_type: char, address: &[u8], data: &[u8]) -> String {
+        let mut bytes = vec![(address.len() + data.len() + 1) as u8];
+        bytes.extend_from_slice(address);
+        bytes.extend_from_slice(data);
+        let sum = bytes.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+        bytes.push(!sum);
+        let hex: String = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        format!("S{}{}\n", record_type, hex)
+    }
+
+    #[test]
+    fn parses_each_data_record_width() {
+        let cases = [
+            ('1', vec![0x12, 0x34], '9'),
+            ('2', vec![0x00, 0x12, 0x34], '8'),
+            ('3', vec![0x00, 0x00, 0x12, 0x34], '7'),
+        ];
+        for (data_type, address, end_type) in cases {
+            let text = record('0', &[0, 0], b"test")
+                + &record(data_type, &address, &[1, 2, 3])
+                + &record(data_type, &bump(&address, 3), &[4])
+                + &record('5', &[0, 2], &[])
+                + &record(end_type, &address, &[]);
+            let rom_set = parse("test.srec", &text).unwrap();
+            assert_eq!(rom_set.segments.len(), 1, "S{}", data_type);
+            assert_eq!(rom_set.segments[0].address, 0x1234);
+            assert_eq!(rom_set.segments[0].data, [1, 2, 3, 4]);
+            assert_eq!(rom_set.entry, Some(0x1234), "S{}", end_type);
+        }
+    }
+
+    fn bump(address: &[u8], by: u8) -> Vec<u8> {
+        let mut address = address.to_vec();
+        *address.last_mut().unwrap() += by;
+        address
+    }
+
+    #[test]
+    fn without_termination_there_is_no_entry() {
+        let rom_set = parse("test.srec", &record('1', &[0, 0], &[0x76])).unwrap();
+        assert_eq!(rom_set.entry, None);
+    }
+
+    #[test]
+    fn rejects_bad_records() {
+        let good = record('1', &[0, 0], &[1]);
+        let mut bad_checksum = good.trim_end().to_string();
+        bad_checksum.replace_range(bad_checksum.len() - 2.., "00");
+        let cases = [
+            (good.clone() + &bad_checksum, 2, "Bad checksum 0x00, expected 0xfa"),
+            (good.clone() + &record('5', &[0, 2], &[]), 2, "Record count is 2, but 1 data records were read"),
+            (good.clone() + &record('9', &[0x01], &[]), 2, "Record too short for its address"),
+            (record('3', &[0, 1, 0, 0], &[1]), 1, "1 bytes at 0x10000 do not fit in memory"),
+            ("X1030000FC\n".to_string(), 1, "Record does not start with 'S'"),
+            ("S4030000FC\n".to_string(), 1, "Unknown record type S4"),
+        ];
+        for (text, expected_line, expected_message) in cases {
+            match parse("test.srec", &text) {
+                Err(LoadError::Parse { line, message }) => {
+                    assert_eq!((line, message.as_str()), (expected_line, expected_message));
+                }
+                other => panic!("unexpected {:?} for {:?}", other, text),
+            }
+        }
+    }
+}
@@ -0,0 +1,194 @@
+// Stack guard. Given the region the stack lives in, reports SP leaving it,
+// pushes landing on loaded code or ROM, and returns popping from above the
+// top of the stack where nothing was ever pushed. In strict mode the CPU
+// treats a violation as an error rather than an event.
+//
+// A violation is reported the first time each instruction makes it, with
+// the SP or address involved. SP leaving the region is reported on the way
+// out rather than for every instruction run while it is outside.
+
+use std::collections::HashSet;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ViolationKind {
+    // SP moved outside the region
+    OutsideRegion { sp: u16 },
+    // A push wrote over protected memory
+    IntoCode { address: u16 },
+    // A return popped from at or above the top of the stack
+    AboveTop { sp: u16 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StackViolation {
+    pub kind: ViolationKind,
+    pub pc: u16,
+}
+
+impl fmt::Display for StackViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ViolationKind::OutsideRegion { sp } => write!(
+                f,
+                "Instruction at 0x{:04x} moved SP to 0x{:04x}, outside the stack region",
+                self.pc, sp
+            ),
+            ViolationKind::IntoCode { address } => write!(
+                f,
+                "Instruction at 0x{:04x} pushed onto 0x{:04x}, which holds code or ROM",
+                self.pc, address
+            ),
+            ViolationKind::AboveTop { sp } => write!(
+                f,
+                "Instruction at 0x{:04x} popped from 0x{:04x}, above the top of the stack",
+                self.pc, sp
+            ),
+        }
+    }
+}
+
+pub struct StackGuard {
+    // Bytes the stack may occupy, inclusive. SP may also sit one past the
+    // end, when the stack is empty.
+    pub start: u16,
+    pub end: u16,
+    // SP before anything was pushed. 0 means the top of memory, as the
+    // first push wraps round to 0xffff.
+    pub top: u16,
+    // Inclusive ranges pushes must not write to
+    pub protected: Vec<(u16, u16)>,
+    // Make violations CPU errors
+    pub strict: bool,
+    // Violations since the debugger last cleared them
+    pub violations: Vec<StackViolation>,
+    reported: HashSet<StackViolation>,
+    // Whether SP was outside the region after the last check, so leaving it
+    // is only reported once
+    outside: bool,
+}
+
+impl StackGuard {
+    pub fn new(start: u16, end: u16, top: u16) -> StackGuard {
+        StackGuard {
+            start,
+            end,
+            top,
+            protected: Vec::new(),
+            strict: false,
+            violations: Vec::new(),
+            reported: HashSet::new(),
+            outside: false,
+        }
+    }
+
+    // Call after each instruction
+    pub fn check_sp(&mut self, pc: u16, sp: u16) {
+        let valid = (self.start as u32..=self.end as u32 + 1).contains(&(sp as u32));
+        if !valid && !self.outside {
+            self.report(ViolationKind::OutsideRegion { sp }, pc);
+        }
+        self.outside = !valid;
+    }
+
+    // Call after a word is pushed, sp being the new SP
+    pub fn push(&mut self, pc: u16, sp: u16) {
+        for address in [sp, sp.wrapping_add(1)] {
+            if self.protected.iter().any(|(start, end)| (*start..=*end).contains(&address)) {
+                self.report(ViolationKind::IntoCode { address }, pc);
+            }
+        }
+    }
+
+    // Call before a word is popped from sp
+    pub fn pop(&mut self, pc: u16, sp: u16) {
+        let top = match self.top {
+            0 => 0x10000,
+            top => top as u32,
+        };
+        if sp as u32 + 2 > top {
+            self.report(ViolationKind::AboveTop { sp }, pc);
+        }
+    }
+
+    fn report(&mut self, kind: ViolationKind, pc: u16) {
+        let violation = StackViolation { kind, pc };
+        if self.reported.insert(violation) {
+            self.violations.push(violation);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{CpuError, I8080};
+
+    #[test]
+    fn strict_mode_reports_every_violation_at_once() {
+        let mut cpu = I8080::new();
+        let mut guard = StackGuard::new(0x0100, 0x01ff, 0x0200);
+        guard.protected.push((0x0000, 0x00ff));
+        guard.strict = true;
+        cpu.stack_guard = Some(Box::new(guard));
+        // CALL 0010 with SP at 0100 pushes onto code and leaves the region
+        cpu.registers.SP = 0x0100;
+        cpu.memory[..3].copy_from_slice(&[0xcd, 0x10, 0x00]);
+
+        let Err(CpuError::Stack(violations)) = cpu.step() else {
+            panic!("expected a stack error");
+        };
+        let kinds: Vec<ViolationKind> = violations.iter().map(|v| v.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                ViolationKind::IntoCode { address: 0x00fe },
+                ViolationKind::IntoCode { address: 0x00ff },
+                ViolationKind::OutsideRegion { sp: 0x00fe },
+            ]
+        );
+        assert!(cpu.stack_guard.as_ref().unwrap().violations.is_empty());
+    }
+
+    #[test]
+    fn reports_each_violation_once() {
+        let mut guard = StackGuard::new(0x0100, 0x01ff, 0x0200);
+        guard.pop(0x10, 0x01fe);
+        assert!(guard.violations.is_empty());
+        guard.pop(0x10, 0x01ff);
+        guard.pop(0x10, 0x01ff);
+        guard.check_sp(0x20, 0x0300);
+        guard.check_sp(0x21, 0x0301);
+        assert_eq!(
+            guard.violations,
+            [
+                StackViolation { kind: ViolationKind::AboveTop { sp: 0x01ff }, pc: 0x10 },
+                StackViolation { kind: ViolationKind::OutsideRegion { sp: 0x0300 }, pc: 0x20 },
+            ]
+        );
+    }
+
+    #[test]
+    fn checks_sp_after_interrupts_and_emulated_returns() {
+        let mut cpu = I8080::new();
+        cpu.stack_guard = Some(Box::new(StackGuard::new(0x0100, 0x01ff, 0x0200)));
+        cpu.registers.SP = 0x0100;
+        cpu.registers.PC = 0x0040;
+        cpu.interrupts_enabled = true;
+        assert!(cpu.interrupt(1));
+        // Back inside for a NOP, then a return from a stack that is empty
+        cpu.registers.SP = 0x0200;
+        cpu.step().unwrap();
+        cpu.return_from_subroutine();
+        let kinds: Vec<ViolationKind> =
+            cpu.stack_guard.as_ref().unwrap().violations.iter().map(|v| v.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                ViolationKind::OutsideRegion { sp: 0x00fe },
+                ViolationKind::AboveTop { sp: 0x0200 },
+                ViolationKind::OutsideRegion { sp: 0x0202 },
+            ]
+        );
+    }
+}
@@ -0,0 +1,60 @@
+use std::env;
+use std::path::Path;
+use std::process::exit;
+
+use intel8080::savestate::Snapshot;
+use intel8080::snapdiff::{diff, DEFAULT_PREVIEW};
+use intel8080::utils::{parse_number, trouble};
+
+const USAGE: &str = "\
+Usage: state-diff [options] <old> <new>
+
+Compares two save states written by emulator --save-state and reports the
+registers, flags and CPU state that changed, the machine's next interrupt
+and device state, and the ranges of memory that differ, showing both sides
+in hex and ASCII. Taking one state before a routine and one after shows
+what it does. States saved from different machines are only reported as
+such.
+
+Options:
+  -h, --help           show this message
+  --preview <n>        bytes of each memory range shown (default 64)
+
+Exit status is 0 when the states match, 1 when they differ and 2 on error.
+";
+
+const EXIT_SAME: i32 = 0;
+const EXIT_DIFFERENT: i32 = 1;
+
+fn main() {
+    let mut paths = Vec::new();
+    let mut preview = DEFAULT_PREVIEW;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                exit(EXIT_SAME);
+            }
+            "--preview" => {
+                let n = args.next().unwrap_or_else(|| trouble("--preview needs a count"));
+                preview = parse_number(&n).unwrap_or_else(|| trouble(&format!("Invalid count '{}'", n)));
+            }
+            _ if arg.starts_with('-') => trouble(&format!("Unknown option '{}', see --help", arg)),
+            _ => paths.push(arg),
+        }
+    }
+    let [old_path, new_path] = paths.as_slice() else {
+        trouble("Give two save states, see --help");
+    };
+    let read = |path: &String| Snapshot::read(Path::new(path)).unwrap_or_else(|e| trouble(&e.to_string()));
+    let (old, new) = (read(old_path), read(new_path));
+    if old.machine != new.machine {
+        println!("Machines differ: {} and {}", old.machine.name(), new.machine.name());
+        exit(EXIT_DIFFERENT);
+    }
+
+    let difference = diff(&old, &new);
+    print!("{}", difference.report(preview));
+    exit(if difference.is_empty() { EXIT_SAME } else { EXIT_DIFFERENT });
+}
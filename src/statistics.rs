@@ -0,0 +1,196 @@
+// Run statistics: how often each opcode executed, how often conditional
+// jumps, calls and returns went each way, memory reads and writes per
+// 256 byte page and I/O per port.
+
+use crate::disassembler::disassemble_instr;
+use crate::watchpoints::Access;
+
+pub struct Statistics {
+    opcodes: [u64; 256],
+    // Taken and not taken counts of the conditional branches, by opcode
+    taken: [u64; 256],
+    not_taken: [u64; 256],
+    page_reads: [u64; 256],
+    page_writes: [u64; 256],
+    port_inputs: [u64; 256],
+    port_outputs: [u64; 256],
+}
+
+impl Default for Statistics {
+    fn default() -> Self {
+        Statistics {
+            opcodes: [0; 256],
+            taken: [0; 256],
+            not_taken: [0; 256],
+            page_reads: [0; 256],
+            page_writes: [0; 256],
+            port_inputs: [0; 256],
+            port_outputs: [0; 256],
+        }
+    }
+}
+
+impl Statistics {
+    pub fn record_opcode(&mut self, opcode: u8) {
+        self.opcodes[opcode as usize] += 1;
+    }
+
+    // Unconditional JMP and CALL pass through here too and aren't counted
+    pub fn record_branch(&mut self, opcode: u8, taken: bool) {
+        if !is_conditional(opcode) {
+            return;
+        }
+        if taken {
+            self.taken[opcode as usize] += 1;
+        } else {
+            self.not_taken[opcode as usize] += 1;
+        }
+    }
+
+    pub fn record_access(&mut self, address: u16, access: Access) {
+        let page = (address >> 8) as usize;
+        match access {
+            Access::Read => self.page_reads[page] += 1,
+            Access::Write => self.page_writes[page] += 1,
+        }
+    }
+
+    pub fn record_port(&mut self, port: u8, access: Access) {
+        match access {
+            Access::Read => self.port_inputs[port as usize] += 1,
+            Access::Write => self.port_outputs[port as usize] += 1,
+        }
+    }
+
+    pub fn report(&self) -> String {
+        let total: u64 = self.opcodes.iter().sum();
+        let mut lines = vec![
+            format!("Opcodes, {} executed", total),
+            "  opcode  instruction        count       %".to_string(),
+        ];
+        let mut opcodes: Vec<usize> = (0..256).filter(|op| self.opcodes[*op] > 0).collect();
+        opcodes.sort_by(|a, b| self.opcodes[*b].cmp(&self.opcodes[*a]).then(a.cmp(b)));
+        for opcode in opcodes {
+            let count = self.opcodes[opcode];
+            lines.push(format!(
+                "  0x{:02x}    {:<10} {:>12}  {:>5.1}%",
+                opcode,
+                mnemonic(opcode as u8),
+                count,
+                count as f64 * 100.0 / total as f64
+            ));
+        }
+
+        lines.push(String::new());
+        lines.push("Conditional branches".to_string());
+        lines.push("  opcode  instruction        taken    not taken   taken %".to_string());
+        for opcode in 0..256 {
+            let (taken, not_taken) = (self.taken[opcode], self.not_taken[opcode]);
+            if taken + not_taken == 0 {
+                continue;
+            }
+            lines.push(format!(
+                "  0x{:02x}    {:<10} {:>12} {:>12}    {:>5.1}%",
+                opcode,
+                mnemonic(opcode as u8),
+                taken,
+                not_taken,
+                taken as f64 * 100.0 / (taken + not_taken) as f64
+            ));
+        }
+
+        lines.push(String::new());
+        lines.push("Memory accesses by page".to_string());
+        lines.push("  page          reads       writes".to_string());
+        for page in 0..256 {
+            let (reads, writes) = (self.page_reads[page], self.page_writes[page]);
+            if reads + writes > 0 {
+                lines.push(format!("  0x{:02x}xx  {:>12} {:>12}", page, reads, writes));
+            }
+        }
+
+        lines.push(String::new());
+        lines.push("Port I/O".to_string());
+        lines.push("  port          in          out".to_string());
+        for port in 0..256 {
+            let (inputs, outputs) = (self.port_inputs[port], self.port_outputs[port]);
+            if inputs + outputs > 0 {
+                lines.push(format!("  0x{:02x}  {:>12} {:>12}", port, inputs, outputs));
+            }
+        }
+
+        let mut text = lines.join("\n");
+        text.push('\n');
+        text
+    }
+}
+
+// Rcc is 11ccc000, Jcc 11ccc010 and Ccc 11ccc100
+fn is_conditional(opcode: u8) -> bool {
+    matches!(opcode & 0b1100_0111, 0b1100_0000 | 0b1100_0010 | 0b1100_0100)
+}
+
+// Instruction without its operands, as "MVI A" for "MVI A,2a"
+fn mnemonic(opcode: u8) -> String {
+    let (text, seek) = disassemble_instr(&[opcode], 0);
+    if seek == 0 {
+        return text;
+    }
+    match text.split_once(',') {
+        Some((mnemonic, _)) => mnemonic.to_string(),
+        None => text.split_whitespace().next().unwrap_or_default().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::I8080;
+
+    // MVI A,fe; INR A; JNZ 0002; OUT 01; HLT, the jump taken once and then
+    // falling through once A wraps to 0
+    fn run() -> Statistics {
+        let mut cpu = I8080::new();
+        cpu.statistics = Some(Box::default());
+        cpu.memory[..9].copy_from_slice(&[0x3e, 0xfe, 0x3c, 0xc2, 0x02, 0x00, 0xd3, 0x01, 0x76]);
+        while !cpu.halted {
+            cpu.step().unwrap();
+        }
+        *cpu.statistics.unwrap()
+    }
+
+    #[test]
+    fn counts_opcodes_and_branches_each_way() {
+        let expected = "\
+Opcodes, 7 executed
+  opcode  instruction        count       %
+  0x3c    INR A                 2   28.6%
+  0xc2    JNZ                   2   28.6%
+  0x3e    MVI A                 1   14.3%
+  0x76    HLT                   1   14.3%
+  0xd3    OUT                   1   14.3%
+
+Conditional branches
+  opcode  instruction        taken    not taken   taken %
+  0xc2    JNZ                   1            1     50.0%
+
+Memory accesses by page
+  page          reads       writes
+
+Port I/O
+  port          in          out
+  0x01             0            1
+";
+        assert_eq!(run().report(), expected);
+    }
+
+    #[test]
+    fn leaves_unconditional_branches_out() {
+        let mut statistics = Statistics::default();
+        statistics.record_branch(0xc3, true);
+        statistics.record_branch(0xcd, true);
+        statistics.record_branch(0xc8, false);
+        assert_eq!(statistics.taken.iter().sum::<u64>(), 0);
+        assert_eq!(statistics.not_taken[0xc8], 1);
+    }
+}
@@ -0,0 +1,98 @@
+// Symbol tables mapping addresses to labels, for showing names in
+// backtraces, listings and reports.
+//
+// Symbol files have one symbol per line, either as
+//   <address> <name>
+// or as an assembler equate
+//   <name> EQU <address>
+// Addresses are hex, with an optional 0x prefix or H suffix. Blank lines and
+// anything after a ';' or '#' are ignored.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use crate::loader::LoadError;
+
+#[derive(Debug, Default, Clone)]
+pub struct Symbols {
+    by_address: BTreeMap<u16, String>,
+}
+
+impl Symbols {
+    pub fn from_file(path: &Path) -> Result<Symbols, LoadError> {
+        let text = fs::read_to_string(path).map_err(|e| LoadError::Io {
+            path: path.to_path_buf(),
+            message: e.to_string(),
+        })?;
+        Symbols::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Symbols, LoadError> {
+        let mut symbols = Symbols::default();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split([';', '#']).next().unwrap_or_default();
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (name, address) = match fields.as_slice() {
+                [] => continue,
+                [address, name] => (*name, *address),
+                [name, equ, address] if equ.eq_ignore_ascii_case("equ") => {
+                    (name.trim_end_matches(':'), *address)
+                }
+                _ => {
+                    return Err(LoadError::Parse {
+                        line: index + 1,
+                        message: "Expected '<address> <name>' or '<name> EQU <address>'".to_string(),
+                    })
+                }
+            };
+            let Some(address) = parse_address(address) else {
+                return Err(LoadError::Parse {
+                    line: index + 1,
+                    message: format!("Invalid address '{}'", address),
+                });
+            };
+            symbols.insert(address, name);
+        }
+        Ok(symbols)
+    }
+
+    pub fn insert(&mut self, address: u16, name: &str) {
+        self.by_address.insert(address, name.to_string());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_address.is_empty()
+    }
+
+    // Label defined exactly at an address
+    pub fn get(&self, address: u16) -> Option<&str> {
+        self.by_address.get(&address).map(String::as_str)
+    }
+
+    // Nearest label at or below an address and the offset from it
+    pub fn lookup(&self, address: u16) -> Option<(&str, u16)> {
+        self.by_address
+            .range(..=address)
+            .next_back()
+            .map(|(start, name)| (name.as_str(), address - start))
+    }
+
+    // An address as name+offset when a label covers it, else as hex
+    pub fn describe(&self, address: u16) -> String {
+        match self.lookup(address) {
+            Some((name, 0)) => format!("0x{:04x} <{}>", address, name),
+            Some((name, offset)) => format!("0x{:04x} <{}+0x{:x}>", address, name, offset),
+            None => format!("0x{:04x}", address),
+        }
+    }
+}
+
+fn parse_address(text: &str) -> Option<u16> {
+    let digits = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix("0X"))
+        .or_else(|| text.strip_suffix(['h', 'H']))
+        .unwrap_or(text);
+    u16::from_str_radix(digits, 16).ok()
+}
@@ -0,0 +1,474 @@
+// Execution traces, one line per instruction in a fixed format so traces of
+// two runs can be compared with diff or the trace-diff tool. Each line shows
+// the state from just before the instruction executed:
+//
+//   0003  cd 10 00  CALL 0010        A=00 F=02 BC=0000 DE=0000 HL=0000 SP=3000 CYC=10
+//
+// Columns are the PC, the instruction's bytes padded to 9 characters, its
+// disassembly padded to 16, then the registers and the CPU cycle count.
+// Operands in the disassembly are zero padded words or bytes, as in
+// "LXI SP,0030" and "MVI A,0f".
+// F is the flags byte as PUSH PSW stores it (S Z 0 AC 0 P 1 C). Numbers are
+// lowercase hex, zero padded, except the cycle count which is decimal.
+//
+// Lines can be limited to instructions within address ranges and to a
+// window of cycles. TraceLine reads lines back for comparing traces, along
+// with lines from other emulators' traces made of name=value pairs.
+
+use std::io::{self, Write};
+
+use crate::cpu::{StatusFlags, I8080};
+use crate::disassembler::disassemble_at;
+
+#[derive(Debug, Clone, Default)]
+pub struct TraceFilter {
+    // Inclusive PC ranges to trace, everything when empty
+    pub ranges: Vec<(u16, u16)>,
+    // Inclusive range of cycle counts to trace, everything when None
+    pub cycles: Option<(u64, u64)>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, cpu: &I8080) -> bool {
+        let pc = cpu.registers.PC;
+        let in_range = self.ranges.is_empty() || self.ranges.iter().any(|(start, end)| (*start..=*end).contains(&pc));
+        let in_window = self.cycles.is_none_or(|(start, end)| (start..=end).contains(&cpu.cycles));
+        in_range && in_window
+    }
+}
+
+pub struct Tracer<W: Write> {
+    output: W,
+    pub filter: TraceFilter,
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(output: W, filter: TraceFilter) -> Tracer<W> {
+        Tracer { output, filter }
+    }
+
+    // Call before executing each instruction
+    pub fn record(&mut self, cpu: &I8080) -> io::Result<()> {
+        if self.filter.matches(cpu) {
+            writeln!(self.output, "{}", format_line(cpu))?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+// Trace line for the instruction at PC
+pub fn format_line(cpu: &I8080) -> String {
+    let r = &cpu.registers;
+    let pc = r.PC as usize;
+    let (text, seek) = disassemble_at(&cpu.memory, r.PC);
+    let bytes: Vec<String> = (0..=seek as usize)
+        .map(|i| format!("{:02x}", cpu.memory[(pc + i) % cpu.memory.len()]))
+        .collect();
+    format!(
+        "{:04x}  {:<9} {:<16} A={:02x} F={:02x} BC={:02x}{:02x} DE={:02x}{:02x} HL={:02x}{:02x} SP={:04x} CYC={}",
+        r.PC,
+        bytes.join(" "),
+        text,
+        r.A,
+        cpu.flags.to_byte(),
+        r.B,
+        r.C,
+        r.D,
+        r.E,
+        r.H,
+        r.L,
+        r.SP,
+        cpu.cycles
+    )
+}
+
+// What a trace line holds. A, F and the register pairs can come together or
+// as halves.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    Pc,
+    A,
+    F,
+    Af,
+    B,
+    C,
+    Bc,
+    D,
+    E,
+    De,
+    H,
+    L,
+    Hl,
+    Sp,
+    Cycles,
+}
+
+const FIELDS: [(&str, Field); 15] = [
+    ("pc", Field::Pc),
+    ("a", Field::A),
+    ("f", Field::F),
+    ("af", Field::Af),
+    ("b", Field::B),
+    ("c", Field::C),
+    ("bc", Field::Bc),
+    ("d", Field::D),
+    ("e", Field::E),
+    ("de", Field::De),
+    ("h", Field::H),
+    ("l", Field::L),
+    ("hl", Field::Hl),
+    ("sp", Field::Sp),
+    ("cycles", Field::Cycles),
+];
+
+// The names fields go by in traces written by other emulators. By default
+// each field goes by its own name, and the cycle count by cyc too. Names are
+// matched ignoring case.
+#[derive(Debug, Clone)]
+pub struct FieldMap {
+    names: Vec<(String, Field)>,
+}
+
+impl Default for FieldMap {
+    fn default() -> Self {
+        let mut names: Vec<(String, Field)> = FIELDS
+            .iter()
+            .map(|(name, field)| (name.to_string(), *field))
+            .collect();
+        names.push(("cyc".to_string(), Field::Cycles));
+        FieldMap { names }
+    }
+}
+
+impl FieldMap {
+    // Renames fields from a comma separated list of field=name, as in
+    // "pc=ip,cycles=t"
+    pub fn rename(&mut self, spec: &str) -> Result<(), String> {
+        for pair in spec.split(',') {
+            let Some((field, name)) = pair.split_once('=') else {
+                return Err(format!("Expected field=name, found '{}'", pair));
+            };
+            let field = FIELDS
+                .iter()
+                .find(|(known, _)| known.eq_ignore_ascii_case(field.trim()))
+                .map(|(_, field)| *field)
+                .ok_or_else(|| format!("Unknown field '{}'", field))?;
+            let name = name.trim().to_ascii_lowercase();
+            if name.is_empty() {
+                return Err(format!("No name given in '{}'", pair));
+            }
+            self.names.retain(|(known, known_field)| *known_field != field && *known != name);
+            self.names.push((name, field));
+        }
+        Ok(())
+    }
+
+    fn field(&self, name: &str) -> Option<Field> {
+        self.names
+            .iter()
+            .find(|(known, _)| known.eq_ignore_ascii_case(name))
+            .map(|(_, field)| *field)
+    }
+}
+
+// A trace line read back in
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceLine {
+    pub pc: u16,
+    pub bytes: Vec<u8>,
+    pub text: String,
+    pub a: u8,
+    pub flags: u8,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+    pub sp: u16,
+    pub cycles: u64,
+}
+
+impl TraceLine {
+    // None for anything not in the trace format, so other output mixed in
+    // with a trace can be skipped
+    pub fn parse(line: &str) -> Option<TraceLine> {
+        let (head, registers) = line.split_once(" A=")?;
+        let pc = parse_hex(head.get(..4)?, 4)?;
+        let bytes = head
+            .get(6..15)?
+            .split_whitespace()
+            .map(|b| u8::from_str_radix(b, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        let text = head.get(16..)?.trim().to_string();
+
+        let registers = format!("A={}", registers);
+        let fields: Vec<(&str, &str)> = registers
+            .split_whitespace()
+            .map(|field| field.split_once('='))
+            .collect::<Option<_>>()?;
+        let [("A", a), ("F", flags), ("BC", bc), ("DE", de), ("HL", hl), ("SP", sp), ("CYC", cycles)] =
+            fields.as_slice()
+        else {
+            return None;
+        };
+        Some(TraceLine {
+            pc,
+            bytes,
+            text,
+            a: parse_hex(a, 2)? as u8,
+            flags: parse_hex(flags, 2)? as u8,
+            bc: parse_hex(bc, 4)?,
+            de: parse_hex(de, 4)?,
+            hl: parse_hex(hl, 4)?,
+            sp: parse_hex(sp, 4)?,
+            cycles: cycles.parse().ok()?,
+        })
+    }
+
+    // Reads a line from another emulator's trace, made of name=value or
+    // name:value pairs separated by spaces or commas, in any order and with
+    // anything unrecognised skipped. Values are hex, optionally written with
+    // a 0x or $ prefix or an h suffix, except the cycle count which is
+    // decimal. PC, SP and every register are needed, and a missing cycle
+    // count reads as 0. The instruction's bytes and text are left empty.
+    pub fn parse_pairs(line: &str, map: &FieldMap) -> Option<TraceLine> {
+        let mut values: Vec<(Field, u64)> = Vec::new();
+        let mut tokens = line
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|token| !token.is_empty());
+        while let Some(token) = tokens.next() {
+            let Some((name, value)) = token.split_once([':', '=']) else {
+                continue;
+            };
+            let Some(field) = map.field(name) else {
+                continue;
+            };
+            // Allowing for "PC: 0100"
+            let value = if value.is_empty() { tokens.next()? } else { value };
+            let value = match field {
+                Field::Cycles => value.parse().ok()?,
+                _ => parse_loose_hex(value)?,
+            };
+            values.push((field, value));
+        }
+
+        let get = |field: Field| {
+            values.iter().find(|(known, _)| *known == field).map(|(_, value)| *value)
+        };
+        let byte = |field: Field, pair: Field, high: bool| {
+            let half = |pair: u64| if high { pair >> 8 } else { pair & 0xff };
+            u8::try_from(get(field).or_else(|| get(pair).map(half))?).ok()
+        };
+        let word = |pair: Field, high: Field, low: Field| {
+            let value = get(pair).or_else(|| Some(get(high)? << 8 | get(low)?))?;
+            u16::try_from(value).ok()
+        };
+        Some(TraceLine {
+            pc: u16::try_from(get(Field::Pc)?).ok()?,
+            bytes: Vec::new(),
+            text: String::new(),
+            a: byte(Field::A, Field::Af, true)?,
+            flags: byte(Field::F, Field::Af, false)?,
+            bc: word(Field::Bc, Field::B, Field::C)?,
+            de: word(Field::De, Field::D, Field::E)?,
+            hl: word(Field::Hl, Field::H, Field::L)?,
+            sp: u16::try_from(get(Field::Sp)?).ok()?,
+            cycles: get(Field::Cycles).unwrap_or(0),
+        })
+    }
+
+    // Describes each way the state differs from other's, empty when they
+    // match. Cycle counts are only compared when asked.
+    pub fn differences(&self, other: &TraceLine, compare_cycles: bool) -> Vec<String> {
+        let mut differences = Vec::new();
+        if self.pc != other.pc {
+            differences.push(format!("PC {:04x} != {:04x}", self.pc, other.pc));
+        }
+        // Traces from other emulators may not have the bytes
+        if self.bytes != other.bytes && !self.bytes.is_empty() && !other.bytes.is_empty() {
+            let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ");
+            differences.push(format!("bytes {} != {}", hex(&self.bytes), hex(&other.bytes)));
+        }
+        let registers = [
+            ("A", self.a as u16, other.a as u16, 2),
+            ("BC", self.bc, other.bc, 4),
+            ("DE", self.de, other.de, 4),
+            ("HL", self.hl, other.hl, 4),
+            ("SP", self.sp, other.sp, 4),
+        ];
+        for (name, left, right, width) in registers {
+            if left != right {
+                differences.push(format!("{} {:0w$x} != {:0w$x}", name, left, right, w = width));
+            }
+        }
+        if self.flags != other.flags {
+            let (left, right) = (StatusFlags::from_byte(self.flags), StatusFlags::from_byte(other.flags));
+            let changed: Vec<&str> = [
+                ("S", left.S != right.S),
+                ("Z", left.Z != right.Z),
+                ("AC", left.AC != right.AC),
+                ("P", left.P != right.P),
+                ("CY", left.C != right.C),
+            ]
+            .iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(name, _)| *name)
+            .collect();
+            differences.push(format!("flags {} ({:02x} != {:02x})", changed.join(" "), self.flags, other.flags));
+        }
+        if compare_cycles && self.cycles != other.cycles {
+            differences.push(format!("cycles {} != {}", self.cycles, other.cycles));
+        }
+        differences
+    }
+}
+
+// Nearest pair of lines at or after i and j that agree again after two
+// traces diverge, skipping as few lines in all as possible and no more than
+// window in either trace
+pub fn realign<T>(
+    left: &[T],
+    right: &[T],
+    i: usize,
+    j: usize,
+    window: usize,
+    agree: impl Fn(&T, &T) -> bool,
+) -> Option<(usize, usize)> {
+    for skipped in 1..=window * 2 {
+        for skip_left in skipped.saturating_sub(window)..=skipped.min(window) {
+            let (l, r) = (i + skip_left, j + skipped - skip_left);
+            if let (Some(left_entry), Some(right_entry)) = (left.get(l), right.get(r)) {
+                if agree(left_entry, right_entry) {
+                    return Some((l, r));
+                }
+            }
+        }
+    }
+    None
+}
+
+// Hex written any of the usual ways: 1f, 0x1f, $1f or 1fh
+fn parse_loose_hex(text: &str) -> Option<u64> {
+    let digits = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix("0X"))
+        .or_else(|| text.strip_prefix('$'))
+        .or_else(|| text.strip_suffix(['h', 'H']))
+        .unwrap_or(text);
+    u64::from_str_radix(digits, 16).ok()
+}
+
+// Exactly width hex digits
+fn parse_hex(text: &str, width: usize) -> Option<u16> {
+    if text.len() != width {
+        return None;
+    }
+    u16::from_str_radix(text, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(program: &[u8]) -> String {
+        let mut cpu = I8080::new();
+        cpu.memory[..program.len()].copy_from_slice(program);
+        format_line(&cpu)
+    }
+
+    #[test]
+    fn pads_operands_to_a_fixed_width() {
+        assert_eq!(
+            line(&[0xcd, 0x00, 0x10]),
+            "0000  cd 00 10  CALL 1000        A=00 F=02 BC=0000 DE=0000 HL=0000 SP=0000 CYC=0"
+        );
+        assert!(line(&[0x31, 0x30, 0x00]).starts_with("0000  31 30 00  LXI SP,0030      A=00"));
+        assert!(line(&[0x3e, 0x0f]).starts_with("0000  3e 0f     MVI A,0f         A=00"));
+        assert!(line(&[0xdb, 0x01]).starts_with("0000  db 01     IN 01            A=00"));
+        assert!(line(&[0xde, 0x05]).starts_with("0000  de 05     SBI 05           A=00"));
+        assert!(line(&[0x76]).starts_with("0000  76        HLT              A=00"));
+    }
+
+    #[test]
+    fn takes_operands_from_the_bottom_of_memory_at_the_top() {
+        let mut cpu = I8080::new();
+        cpu.memory[0xffff] = 0xc3;
+        cpu.memory[..2].copy_from_slice(&[0x34, 0x12]);
+        cpu.registers.PC = 0xffff;
+        assert!(format_line(&cpu).starts_with("ffff  c3 34 12  JMP 1234         A=00"));
+    }
+
+    #[test]
+    fn reads_back_what_it_writes() {
+        let text = line(&[0x21, 0x34, 0x12]);
+        let parsed = TraceLine::parse(&text).unwrap();
+        assert_eq!(parsed.pc, 0);
+        assert_eq!(parsed.bytes, [0x21, 0x34, 0x12]);
+        assert_eq!(parsed.text, "LXI H,1234");
+        assert_eq!(parsed.flags, 0x02);
+        assert_eq!(TraceLine::parse("Limit reached after 1 instructions"), None);
+    }
+
+    #[test]
+    fn reads_name_value_pairs() {
+        let map = FieldMap::default();
+        let line = "PC: 0103, AF: 4202, BC=0000 DE=$0010 HL=12h SP=0x00f0 CYC=17 ; LXI H,0012";
+        let parsed = TraceLine::parse_pairs(line, &map).unwrap();
+        assert_eq!((parsed.pc, parsed.a, parsed.flags), (0x103, 0x42, 0x02));
+        assert_eq!((parsed.bc, parsed.de, parsed.hl, parsed.sp), (0, 0x10, 0x12, 0xf0));
+        assert_eq!(parsed.cycles, 17);
+        assert!(parsed.bytes.is_empty());
+
+        let native = TraceLine::parse(&line_at(0x103)).unwrap();
+        let split = "a:00 f:02 b:00 c:00 d:00 e:00 h:00 l:00 sp:0000 pc:0103";
+        let split = TraceLine::parse_pairs(split, &map).unwrap();
+        assert_eq!(native.differences(&split, false), Vec::<String>::new());
+
+        // Missing registers and out of range values aren't trace lines
+        assert_eq!(TraceLine::parse_pairs("PC=0100 SP=0000", &map), None);
+        assert_eq!(TraceLine::parse_pairs("PC=10000 AF=0 BC=0 DE=0 HL=0 SP=0", &map), None);
+    }
+
+    #[test]
+    fn renames_fields() {
+        let mut map = FieldMap::default();
+        map.rename("pc=ip, cycles=t").unwrap();
+        let line = "ip=0005 af=0002 bc=0 de=0 hl=0 sp=0 t=9 pc=ffff";
+        let parsed = TraceLine::parse_pairs(line, &map).unwrap();
+        assert_eq!((parsed.pc, parsed.cycles), (5, 9));
+
+        assert_eq!(map.rename("pc").unwrap_err(), "Expected field=name, found 'pc'");
+        assert_eq!(map.rename("ix=ix").unwrap_err(), "Unknown field 'ix'");
+        assert_eq!(map.rename("sp=").unwrap_err(), "No name given in 'sp='");
+    }
+
+    fn line_at(pc: u16) -> String {
+        let mut cpu = I8080::new();
+        cpu.registers.PC = pc;
+        format_line(&cpu)
+    }
+
+    #[test]
+    fn realigns_past_lines_only_in_the_left_trace() {
+        let left = [1, 2, 7, 8, 3, 4];
+        let right = [1, 2, 3, 4];
+        assert_eq!(realign(&left, &right, 2, 2, 10, |l, r| l == r), Some((4, 2)));
+    }
+
+    #[test]
+    fn realigns_past_lines_only_in_the_right_trace() {
+        let left = [1, 2, 3, 4];
+        let right = [1, 2, 9, 3, 4];
+        assert_eq!(realign(&left, &right, 2, 2, 10, |l, r| l == r), Some((2, 3)));
+    }
+
+    #[test]
+    fn gives_up_realigning_beyond_the_window() {
+        let left = [1, 7, 7, 7, 2];
+        let right = [1, 2];
+        assert_eq!(realign(&left, &right, 1, 1, 2, |l, r| l == r), None);
+        assert_eq!(realign(&left, &right, 1, 1, 3, |l, r| l == r), Some((4, 1)));
+    }
+}
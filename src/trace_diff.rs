@@ -0,0 +1,231 @@
+use std::env;
+use std::fs;
+use std::process::exit;
+
+use intel8080::trace::{realign, FieldMap, TraceLine};
+use intel8080::utils::{parse_number, trouble};
+
+const USAGE: &str = "\
+Usage: trace-diff [options] <trace> <trace>
+
+Compares two traces and reports each instruction where the PC, registers,
+flags or cycle count differ, with the instructions around it. Traces can be
+written by emulator --trace or by another emulator, as lines of name=value
+or name:value pairs such as 'PC:0100 AF:0002 BC:0000 DE:0000 HL:0000
+SP:f000 CYC:7'. Lines that are neither are skipped.
+
+The traces are aligned on cycle count, so one may start later than the
+other, as when recorded with --trace-cycles. With --ignore-cycles they are
+compared from their first lines. After a divergence they are re-aligned at
+the nearest point where they agree again, so an extra or missing stretch of
+instructions in one of them is reported once rather than ending the
+comparison. Cycle counts are then compared relative to the difference
+between them at that point.
+
+Options:
+  -h, --help           show this message
+  --context <n>        instructions shown before and after each divergence
+                       (default 5)
+  --ignore-cycles      don't compare or align on cycle counts, for traces
+                       from emulators with different timings
+  --fields <map>       names other traces use for fields, as field=name pairs
+                       separated by commas, such as 'pc=ip,cycles=t'. Fields
+                       are pc, a, f, af, b, c, bc, d, e, de, h, l, hl, sp
+                       and cycles, and by default go by those names
+  --window <n>         instructions looked through in each trace for a place
+                       to re-align after a divergence (default 1000)
+  --max-divergences <n>
+                       stop after n divergences (default 10)
+
+Exit status is 0 when the traces match, 1 when they differ and 2 on error,
+which includes a trace without any lines and traces that don't overlap.
+";
+
+const EXIT_SAME: i32 = 0;
+const EXIT_DIFFERENT: i32 = 1;
+
+const DEFAULT_CONTEXT: usize = 5;
+const DEFAULT_WINDOW: usize = 1000;
+const DEFAULT_MAX_DIVERGENCES: usize = 10;
+
+// A parsed line and where it came from
+struct Entry {
+    number: usize,
+    text: String,
+    line: TraceLine,
+}
+
+fn main() {
+    let mut paths = Vec::new();
+    let mut context = DEFAULT_CONTEXT;
+    let mut window = DEFAULT_WINDOW;
+    let mut max_divergences = DEFAULT_MAX_DIVERGENCES;
+    let mut compare_cycles = true;
+    let mut fields = FieldMap::default();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut count = |what: &str| {
+            let n = args.next().unwrap_or_else(|| trouble(&format!("{} needs {}", arg, what)));
+            parse_number(&n).unwrap_or_else(|| trouble(&format!("Invalid count '{}'", n)))
+        };
+        match arg.as_str() {
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                exit(EXIT_SAME);
+            }
+            "--context" => context = count("a count"),
+            "--window" => window = count("a count"),
+            "--max-divergences" => max_divergences = count("a count").max(1),
+            "--ignore-cycles" => compare_cycles = false,
+            "--fields" => {
+                let spec = args.next().unwrap_or_else(|| trouble("--fields needs a map"));
+                fields.rename(&spec).unwrap_or_else(|e| trouble(&e));
+            }
+            _ if arg.starts_with('-') => trouble(&format!("Unknown option '{}', see --help", arg)),
+            _ => paths.push(arg),
+        }
+    }
+    let [left_path, right_path] = paths.as_slice() else {
+        trouble("Give two trace files, see --help");
+    };
+    let left = read_trace(left_path, &fields);
+    let right = read_trace(right_path, &fields);
+    for (path, trace) in [(left_path, &left), (right_path, &right)] {
+        if trace.is_empty() {
+            trouble(&format!("No trace lines in {}", path));
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    if compare_cycles {
+        while let (Some(l), Some(r)) = (left.get(i), right.get(j)) {
+            if l.line.cycles < r.line.cycles {
+                i += 1;
+            } else if r.line.cycles < l.line.cycles {
+                j += 1;
+            } else {
+                break;
+            }
+        }
+        if i == left.len() || j == right.len() {
+            trouble("The traces don't overlap, one ends before the other starts");
+        }
+    }
+
+    // How far the right trace's cycle count is ahead of the left's. Starts
+    // at 0 and changes when re-aligning skips instructions in one trace.
+    let mut offset: i128 = 0;
+    let mut divergences = 0;
+    loop {
+        let agree = |l: &Entry, r: &Entry| differences(l, r, compare_cycles.then_some(offset)).is_empty();
+        let matched = left[i..]
+            .iter()
+            .zip(&right[j..])
+            .take_while(|(l, r)| agree(l, r))
+            .count();
+        let (i_end, j_end) = (i + matched, j + matched);
+        let matching = match divergences {
+            0 => format!("{} matching instructions", matched),
+            _ => format!("{} more matching instructions", matched),
+        };
+        let (l, r) = (left.get(i_end), right.get(j_end));
+        match (l, r) {
+            (None, None) if divergences == 0 => {
+                println!("Traces match over {}", matching);
+                exit(EXIT_SAME);
+            }
+            (None, None) => {
+                println!("Traces end after {}", matching);
+                break;
+            }
+            (Some(l), Some(r)) => {
+                let which = if divergences == 0 { "First divergence" } else { "Divergence" };
+                println!(
+                    "{} after {}, at {}:{} and {}:{}",
+                    which, matching, left_path, l.number, right_path, r.number
+                );
+                for difference in differences(l, r, compare_cycles.then_some(offset)) {
+                    println!("  {}", difference);
+                }
+            }
+            (Some(l), None) => println!(
+                "{} ends after {}, {} continues at line {}",
+                right_path, matching, left_path, l.number
+            ),
+            (None, Some(r)) => println!(
+                "{} ends after {}, {} continues at line {}",
+                left_path, matching, right_path, r.number
+            ),
+        }
+        divergences += 1;
+
+        println!();
+        for entry in &left[i_end.saturating_sub(context).max(i)..i_end] {
+            println!("  {}", entry.text);
+        }
+        for entry in left.iter().skip(i_end).take(context + 1) {
+            println!("- {}", entry.text);
+        }
+        for entry in right.iter().skip(j_end).take(context + 1) {
+            println!("+ {}", entry.text);
+        }
+        println!();
+
+        if l.is_none() || r.is_none() {
+            break;
+        }
+        if divergences == max_divergences {
+            println!("Stopping after {} divergences", divergences);
+            break;
+        }
+        // Cycle counts are bound to differ after skipping instructions
+        let agree = |l: &Entry, r: &Entry| differences(l, r, None).is_empty();
+        let Some((next_i, next_j)) = realign(&left, &right, i_end, j_end, window, agree) else {
+            println!("Could not re-align within {} instructions of the divergence", window);
+            break;
+        };
+        println!(
+            "Re-aligned at {}:{} and {}:{}, skipping {} and {} instructions",
+            left_path,
+            left[next_i].number,
+            right_path,
+            right[next_j].number,
+            next_i - i_end,
+            next_j - j_end
+        );
+        (i, j) = (next_i, next_j);
+        offset = right[j].line.cycles as i128 - left[i].line.cycles as i128;
+    }
+    exit(EXIT_DIFFERENT);
+}
+
+// Differences between two lines, comparing cycle counts when given the
+// offset expected between them
+fn differences(l: &Entry, r: &Entry, offset: Option<i128>) -> Vec<String> {
+    let mut differences = l.line.differences(&r.line, false);
+    if let Some(offset) = offset {
+        let (left, right) = (l.line.cycles, r.line.cycles);
+        if right as i128 - left as i128 != offset {
+            differences.push(match offset {
+                0 => format!("cycles {} != {}", left, right),
+                _ => format!("cycles {} != {}, expected a difference of {:+}", left, right, offset),
+            });
+        }
+    }
+    differences
+}
+
+fn read_trace(path: &str, fields: &FieldMap) -> Vec<Entry> {
+    let text = fs::read_to_string(path).unwrap_or_else(|e| trouble(&format!("Could not read {}: {}", path, e)));
+    text.lines()
+        .enumerate()
+        .filter_map(|(index, text)| {
+            let line = TraceLine::parse(text).or_else(|| TraceLine::parse_pairs(text, fields));
+            line.map(|line| Entry {
+                number: index + 1,
+                text: text.to_string(),
+                line,
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,145 @@
+// Uninitialised memory. A shadow bitmap tracks which bytes hold a value the
+// program put there or was loaded with, and reads of any other byte are
+// reported with the instruction that made them. Each instruction and
+// address pair is reported once. ROM counts as initialised whether or not
+// anything was loaded into it, as the program has no way to write it.
+//
+// Real RAM powers on holding junk rather than zeroes, so memory can also be
+// filled with a pattern or random bytes before loading to shake out
+// programs that get away with relying on it.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::cpu::MEMORY_SIZE;
+use crate::loader::RomSet;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UninitRead {
+    pub pc: u16,
+    pub address: u16,
+}
+
+impl fmt::Display for UninitRead {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Instruction at 0x{:04x} read 0x{:04x}, which has not been written",
+            self.pc, self.address
+        )
+    }
+}
+
+pub struct UninitDetector {
+    initialised: Vec<bool>,
+    reported: HashSet<(u16, u16)>,
+    // Reads since the debugger last cleared them
+    pub reads: Vec<UninitRead>,
+}
+
+impl Default for UninitDetector {
+    fn default() -> Self {
+        UninitDetector {
+            initialised: vec![false; MEMORY_SIZE],
+            reported: HashSet::new(),
+            reads: Vec::new(),
+        }
+    }
+}
+
+impl UninitDetector {
+    pub fn mark(&mut self, address: u16) {
+        self.initialised[address as usize] = true;
+    }
+
+    // Everything loaded, ROM and RAM images alike, starts initialised
+    pub fn mark_loaded(&mut self, rom_set: &RomSet) {
+        for segment in &rom_set.segments {
+            let start = segment.address as usize;
+            self.initialised[start..start + segment.data.len()].fill(true);
+        }
+    }
+
+    // Inclusive range, for ROM
+    pub fn mark_range(&mut self, start: u16, end: u16) {
+        self.initialised[start as usize..=end as usize].fill(true);
+    }
+
+    // For memory restored wholesale, as from a save state
+    pub fn mark_all(&mut self) {
+        self.initialised.fill(true);
+    }
+
+    pub fn read(&mut self, pc: u16, address: u16) {
+        if !self.initialised[address as usize] && self.reported.insert((pc, address)) {
+            self.reads.push(UninitRead { pc, address });
+        }
+    }
+}
+
+// What memory holds at power on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fill {
+    Byte(u8),
+    // xorshift64 from the seed, so a run can be repeated
+    Random(u64),
+}
+
+impl Fill {
+    pub fn apply(&self, memory: &mut [u8]) {
+        match *self {
+            Fill::Byte(byte) => memory.fill(byte),
+            Fill::Random(seed) => {
+                // Zero would stay zero forever
+                let mut state = seed.max(1);
+                for byte in memory.iter_mut() {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    *byte = (state >> 56) as u8;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::I8080;
+    use crate::loader::Segment;
+
+    #[test]
+    fn reports_reads_of_bytes_neither_written_nor_loaded() {
+        let mut cpu = I8080::new();
+        let mut uninit = UninitDetector::default();
+        uninit.mark_range(0x4000, 0x4fff);
+        cpu.uninit = Some(Box::new(uninit));
+        // LDA 2000; STA 2000; LDA 2000; LDA 0000; LDA 4000; HLT
+        let program = vec![
+            0x3a, 0x00, 0x20, 0x32, 0x00, 0x20, 0x3a, 0x00, 0x20, 0x3a, 0x00, 0x00, 0x3a, 0x00,
+            0x40, 0x76,
+        ];
+        let rom_set = RomSet {
+            segments: vec![Segment {
+                name: "test".to_string(),
+                address: 0,
+                data: program,
+                read_only: false,
+            }],
+            entry: None,
+        };
+        cpu.load_rom_set(&rom_set);
+        while !cpu.halted {
+            cpu.step().unwrap();
+        }
+        // Only the read before the write, the program's own bytes and the
+        // ROM range were initialised
+        let reads = &cpu.uninit.as_ref().unwrap().reads;
+        assert_eq!(reads, &[UninitRead { pc: 0x0000, address: 0x2000 }]);
+        assert_eq!(
+            reads[0].to_string(),
+            "Instruction at 0x0000 read 0x2000, which has not been written"
+        );
+    }
+}
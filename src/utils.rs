@@ -1,19 +1,4 @@
-use std::{env, fs::File, process::exit};
-use std::io::prelude::*;
-
-pub fn load_file(buffer: &mut Vec<u8>) {
-    let args: Vec<String> = env::args().collect();
-    let file_path = &args[1];
-
-    let mut file = match File::open(file_path) {
-       Ok(file) => file,
-       Err(_) => {
-           terminate("Could not open file");
-       }
-    };
-
-    file.read_to_end(buffer).unwrap();
-}
+use std::process::exit;
 
 pub fn merge_bytes(left: u8, right: u8) -> u16 {
     (left as u16) << 8 | right as u16
@@ -24,6 +9,53 @@ pub fn terminate(message: &str) -> ! {
     exit(1);
 }
 
+// Like terminate() but with diff's exit status for trouble, for the tools
+// comparing traces and states where 1 means they differ
+pub fn trouble(message: &str) -> ! {
+    eprintln!("{}", message);
+    exit(2);
+}
+
 pub fn check_even_parity(data: u8) -> bool {
-    data.count_ones() % 2 == 0 
+    data.count_ones().is_multiple_of(2)
+}
+
+// Parses decimal or 0x-prefixed hexadecimal numbers
+pub fn parse_number(text: &str) -> Option<usize> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+// Decodes a string of hex digit pairs into bytes
+pub fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.is_ascii() || !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+// Formats bytes as lines of 16 in hex with an ASCII column, labelled with
+// the address of the first byte on each line
+pub fn hexdump(data: &[u8], address: u16) -> Vec<String> {
+    data.chunks(16)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+            let ascii: String = chunk
+                .iter()
+                .map(|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' })
+                .collect();
+            format!(
+                "{:04x}  {:<47}  {}",
+                address.wrapping_add(i as u16 * 16),
+                hex.join(" "),
+                ascii
+            )
+        })
+        .collect()
 }
@@ -0,0 +1,89 @@
+// Watchpoints stop the debugger when an instruction touches a range of
+// memory. The CPU checks them on every data access and records hits for the
+// debugger to pick up once the instruction has finished.
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    // Either reads or writes
+    Access,
+}
+
+impl WatchKind {
+    pub fn matches(&self, access: Access) -> bool {
+        match self {
+            WatchKind::Read => access == Access::Read,
+            WatchKind::Write => access == Access::Write,
+            WatchKind::Access => true,
+        }
+    }
+}
+
+impl fmt::Display for WatchKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WatchKind::Read => write!(f, "read"),
+            WatchKind::Write => write!(f, "write"),
+            WatchKind::Access => write!(f, "access"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watchpoint {
+    pub start: u16,
+    // Inclusive
+    pub end: u16,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    pub fn contains(&self, address: u16) -> bool {
+        self.start <= address && address <= self.end
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} 0x{:04x}-0x{:04x}", self.kind, self.start, self.end)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WatchHit {
+    // Address of the instruction that made the access
+    pub pc: u16,
+    pub address: u16,
+    pub access: Access,
+    // Kind of the first watchpoint the access matched
+    pub kind: WatchKind,
+    // Same as new for reads
+    pub old: u8,
+    pub new: u8,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.access {
+            Access::Read => write!(
+                f,
+                "Read 0x{:04x} at PC 0x{:04x}, value 0x{:02x}",
+                self.address, self.pc, self.new
+            ),
+            Access::Write => write!(
+                f,
+                "Write 0x{:04x} at PC 0x{:04x}, 0x{:02x} -> 0x{:02x}",
+                self.address, self.pc, self.old, self.new
+            ),
+        }
+    }
+}